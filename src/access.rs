@@ -0,0 +1,141 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+use ipnet::IpNet;
+use routerify::ext::RequestExt;
+use crate::http::Request;
+
+const FORWARDED_HEADER: &str = "Forwarded";
+const X_FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+#[derive(Debug, Clone, Default)]
+pub struct AccessConfig {
+    pub default_allowed: Vec<IpNet>,
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl AccessConfig {
+    /// Resolves the address of the client, only honoring forwarding headers
+    /// if the request came in through one of the trusted proxies.
+    pub fn client_ip(&self, req: &Request) -> IpAddr {
+        let peer = req.remote_addr().ip();
+
+        if !self.is_trusted_proxy(peer) {
+            return peer;
+        }
+
+        let forwarded = req.headers()
+            .get(FORWARDED_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(parse_forwarded)
+            .or_else(|| {
+                req.headers()
+                    .get(X_FORWARDED_FOR_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .map(parse_x_forwarded_for)
+            })
+            .unwrap_or_default();
+
+        // Walk the chain from the closest hop, the first address that is not
+        // one of our own proxies is the one we can't verify any further.
+        forwarded.into_iter()
+            .rev()
+            .find(|ip| !self.is_trusted_proxy(*ip))
+            .unwrap_or(peer)
+    }
+
+    /// Checks the client against the allowlist of a hook, falling back to the
+    /// global default if the hook has none. An empty allowlist allows everyone.
+    pub fn is_allowed(&self, ip: IpAddr, hook_allowed: &[IpNet]) -> bool {
+        let allowed = if hook_allowed.is_empty() {
+            &self.default_allowed
+        } else {
+            hook_allowed
+        };
+
+        allowed.is_empty() || allowed.iter().any(|net| net.contains(&ip))
+    }
+
+    fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+}
+
+pub fn parse_ip_net(value: &str) -> Result<IpNet, String> {
+    IpNet::from_str(value)
+        .or_else(|_| IpAddr::from_str(value).map(IpNet::from))
+        .map_err(|_| format!("Invalid IP address or CIDR range: {}", value))
+}
+
+fn parse_x_forwarded_for(value: &str) -> Vec<IpAddr> {
+    value.split(',')
+        .filter_map(|addr| parse_node(addr.trim()))
+        .collect()
+}
+
+fn parse_forwarded(value: &str) -> Vec<IpAddr> {
+    value.split(',')
+        .flat_map(|element| element.split(';'))
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(node)) if key.eq_ignore_ascii_case("for") => {
+                    parse_node(node.trim_matches('"'))
+                },
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = IpAddr::from_str(node) {
+        return Some(ip);
+    }
+
+    if let Ok(addr) = SocketAddr::from_str(node) {
+        return Some(addr.ip());
+    }
+
+    // Bracketed IPv6 without a port, e.g. `[2001:db8::1]`
+    node.strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .and_then(|n| IpAddr::from_str(n).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_forwarded_header() {
+        let value = r#"for=192.0.2.60;proto=http;by=203.0.113.43, For="[2001:db8:cafe::17]:4711", for=unknown"#;
+        let parsed = parse_forwarded(value);
+        assert_eq!(parsed, vec![
+            IpAddr::from_str("192.0.2.60").unwrap(),
+            IpAddr::from_str("2001:db8:cafe::17").unwrap(),
+        ]);
+    }
+
+    #[test]
+    fn parse_x_forwarded_for_header() {
+        let parsed = parse_x_forwarded_for("203.0.113.7, 10.0.0.2:8080");
+        assert_eq!(parsed, vec![
+            IpAddr::from_str("203.0.113.7").unwrap(),
+            IpAddr::from_str("10.0.0.2").unwrap(),
+        ]);
+    }
+
+    #[test]
+    fn hook_allowlist_overrides_default() {
+        let config = AccessConfig {
+            default_allowed: vec![parse_ip_net("10.0.0.0/8").unwrap()],
+            trusted_proxies: vec![],
+        };
+        let ip = IpAddr::from_str("192.168.1.10").unwrap();
+
+        assert!(!config.is_allowed(ip, &[]));
+        assert!(config.is_allowed(ip, &[parse_ip_net("192.168.1.0/24").unwrap()]));
+    }
+}
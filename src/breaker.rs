@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use crate::{
+    http::StatusCode,
+    error::DeliveryError,
+};
+
+/// Discord's error code for webhooks that were deleted
+const UNKNOWN_WEBHOOK: u64 = 10015;
+
+/// Counts consecutive failed deliveries per destination, to stop posting to dead webhooks.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    /// Failed deliveries in a row that open the circuit, zero never opens it
+    threshold: u32,
+    /// Webhook told about hooks that were disabled
+    admin_url: Option<String>,
+    failures: HashMap<String, u32>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, admin_url: Option<String>) -> Self {
+        Self {
+            threshold,
+            admin_url,
+            failures: HashMap::new(),
+        }
+    }
+
+    pub fn admin_url(&self) -> Option<&str> {
+        self.admin_url.as_deref()
+    }
+
+    /// Records the outcome of a delivery, returning the number of failures in a row
+    /// if this one opened the circuit.
+    pub fn record(&mut self, destination: &str, success: bool) -> Option<u32> {
+        if success {
+            self.failures.remove(destination);
+            return None;
+        }
+
+        let failures = self.failures.entry(destination.to_string()).or_default();
+        *failures += 1;
+
+        Some(*failures).filter(|failures| self.threshold > 0 && *failures == self.threshold)
+    }
+
+    /// Closes the circuit of a destination again.
+    pub fn reset(&mut self, destination: &str) {
+        self.failures.remove(destination);
+    }
+}
+
+/// Whether a delivery failed because the webhook is gone or its token is invalid.
+///
+/// Outages, rate limits and messages Discord refused say nothing about the webhook itself.
+pub fn is_dead_destination(err: &DeliveryError) -> bool {
+    match err {
+        DeliveryError::Rejected { status: StatusCode::UNAUTHORIZED, .. } => true,
+        // Threads and messages that are gone are not found either
+        DeliveryError::Rejected { status: StatusCode::NOT_FOUND, body } => {
+            let code = serde_json::from_str::<serde_json::Value>(body).ok()
+                .and_then(|body| body["code"].as_u64());
+            matches!(code, None | Some(UNKNOWN_WEBHOOK))
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuits_open_once_after_consecutive_failures() {
+        let mut breaker = CircuitBreaker::new(3, None);
+        let url = "https://discord.test/api/webhooks/1/token";
+
+        assert_eq!(breaker.record(url, false), None);
+        assert_eq!(breaker.record(url, true), None);
+        assert_eq!(breaker.record(url, false), None);
+        assert_eq!(breaker.record(url, false), None);
+        assert_eq!(breaker.record(url, false), Some(3));
+        assert_eq!(breaker.record(url, false), None);
+
+        breaker.reset(url);
+        assert_eq!(breaker.record(url, false), None);
+    }
+
+    #[test]
+    fn only_dead_webhooks_count() {
+        let rejected = |status, body: &str| DeliveryError::Rejected { status, body: body.into() };
+
+        assert!(is_dead_destination(&rejected(StatusCode::NOT_FOUND, r#"{"message": "Unknown Webhook", "code": 10015}"#)));
+        assert!(is_dead_destination(&rejected(StatusCode::UNAUTHORIZED, r#"{"message": "Invalid Webhook Token", "code": 50027}"#)));
+        assert!(!is_dead_destination(&rejected(StatusCode::NOT_FOUND, r#"{"message": "Unknown Channel", "code": 10003}"#)));
+        assert!(!is_dead_destination(&rejected(StatusCode::BAD_REQUEST, r#"{"message": "Invalid Form Body", "code": 50035}"#)));
+        assert!(!is_dead_destination(&rejected(StatusCode::BAD_GATEWAY, "")));
+        assert!(!is_dead_destination(&DeliveryError::Request("connection refused".into())));
+    }
+}
@@ -0,0 +1,518 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::PathBuf,
+    time::Duration,
+    convert::TryFrom,
+};
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use crate::{
+    models::discord::{Embed, Title, Footer, Color},
+    store::{HookConfig, HookId, DeliveryStatus},
+    routes::hooks::{post_embeds, record_outcome, disable_destination},
+    http::Client as HttpClient,
+    error::EventError,
+    Result,
+    HookRegistry,
+    Digests,
+    DeliveryLog,
+    DeliveryQueues,
+    CircuitBreaker,
+    OutboundLimiter,
+};
+
+const STORAGE_ROOT: &str = "./data";
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Collects events of a hook and sends them as one message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DigestConfig {
+    /// Seconds to collect events for, zero sends every event right away
+    pub window: u64,
+    /// Send the digest early once this many events were collected
+    pub max_events: usize,
+    /// GitLab events to collect, e.g. `Push Hook`
+    pub events: Vec<String>,
+}
+
+impl Default for DigestConfig {
+    fn default() -> Self {
+        Self {
+            window: 0,
+            max_events: 25,
+            events: vec!["Push Hook".into(), "Pipeline Hook".into()],
+        }
+    }
+}
+
+impl DigestConfig {
+    pub fn collects(&self, event: &str) -> bool {
+        self.window > 0 && self.events.iter().any(|e| e == event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SummaryPeriod {
+    Daily,
+    /// Sent on Mondays
+    Weekly,
+}
+
+/// Periodically sends a summary of the activity seen by a hook.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SummaryConfig {
+    pub period: Option<SummaryPeriod>,
+    /// Hour of the day the summary is sent at, in UTC
+    pub hour: u32,
+}
+
+impl Default for SummaryConfig {
+    fn default() -> Self {
+        Self {
+            period: None,
+            hour: 9,
+        }
+    }
+}
+
+impl SummaryConfig {
+    /// The first time a summary is due after `since`.
+    fn next_due(&self, period: SummaryPeriod, since: DateTime<Utc>) -> DateTime<Utc> {
+        let mut due = since.naive_utc().date()
+            .and_hms_opt(self.hour, 0, 0)
+            .map(|naive| Utc.from_utc_datetime(&naive))
+            .unwrap_or(since);
+
+        while due <= since || (period == SummaryPeriod::Weekly && due.weekday() != chrono::Weekday::Mon) {
+            due = due + chrono::Duration::days(1);
+        }
+
+        due
+    }
+}
+
+/// Activity counted towards the scheduled summaries.
+#[derive(Debug, Clone, Copy)]
+pub enum Activity {
+    Push { commits: u64 },
+    MergeRequestMerged,
+    IssueClosed,
+    Pipeline { success: bool },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Stats {
+    pub pushes: u64,
+    pub commits: u64,
+    pub merge_requests_merged: u64,
+    pub issues_closed: u64,
+    pub pipelines_succeeded: u64,
+    pub pipelines_failed: u64,
+}
+
+impl Stats {
+    fn record(&mut self, activity: Activity) {
+        match activity {
+            Activity::Push { commits } => {
+                self.pushes += 1;
+                self.commits += commits;
+            },
+            Activity::MergeRequestMerged => self.merge_requests_merged += 1,
+            Activity::IssueClosed => self.issues_closed += 1,
+            Activity::Pipeline { success: true } => self.pipelines_succeeded += 1,
+            Activity::Pipeline { success: false } => self.pipelines_failed += 1,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pushes == 0 && self.merge_requests_merged == 0 && self.issues_closed == 0
+            && self.pipelines_succeeded == 0 && self.pipelines_failed == 0
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let pipelines = self.pipelines_succeeded + self.pipelines_failed;
+        let mut lines = vec![
+            format!("**Merge requests merged:** {}", self.merge_requests_merged),
+            format!("**Issues closed:** {}", self.issues_closed),
+            format!("**Pushes:** {} ({} commits)", self.pushes, self.commits),
+        ];
+
+        if pipelines > 0 {
+            let rate = self.pipelines_succeeded as f64 / pipelines as f64 * 100.0;
+            lines.push(format!(
+                "**Pipelines:** {} succeeded, {} failed ({:.0}% success rate)",
+                self.pipelines_succeeded, self.pipelines_failed, rate
+            ));
+        }
+
+        lines
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SummaryState {
+    period_started: DateTime<Utc>,
+    stats: Stats,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    /// Delivery record of the event, updated once the digest is sent
+    record_id: uuid::Uuid,
+    event: String,
+    footer: Footer,
+    line: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Batch {
+    started: DateTime<Utc>,
+    /// Held back until the schedule of the hook opens, regardless of the digest settings
+    opens: Option<DateTime<Utc>>,
+    entries: Vec<Entry>,
+}
+
+/// A digest or summary that is due, rendered for its hook.
+struct Due {
+    hook: HookConfig,
+    embeds: Vec<Embed>,
+    /// Delivery records of the events in a digest
+    records: Vec<uuid::Uuid>,
+}
+
+/// Events waiting to be sent as a digest and activity waiting for the next summary.
+pub struct DigestStore {
+    batches: HashMap<HookId, Batch>,
+    summaries: HashMap<HookId, SummaryState>,
+}
+
+impl DigestStore {
+    pub fn load() -> Result<Self> {
+        let batches = load_all_batches()?;
+        let summaries = load_all_summaries()?;
+        Ok(Self { batches, summaries })
+    }
+
+    pub async fn push(&mut self, hook_id: &HookId, record_id: uuid::Uuid, event: &str, embed: &Embed, opens: Option<DateTime<Utc>>) -> Result<()> {
+        let batch = self.batches.entry(hook_id.clone()).or_insert_with(|| Batch {
+            started: Utc::now(),
+            opens: None,
+            entries: Vec::new(),
+        });
+        batch.opens = batch.opens.max(opens);
+
+        batch.entries.push(Entry {
+            record_id,
+            event: event.to_string(),
+            footer: embed.footer.clone(),
+            line: embed.digest_line(),
+        });
+
+        store_batch(hook_id, batch)?;
+        Ok(())
+    }
+
+    pub async fn record(&mut self, hook_id: &HookId, activity: Activity) -> Result<()> {
+        let state = self.summaries.entry(hook_id.clone()).or_insert_with(|| SummaryState {
+            period_started: Utc::now(),
+            stats: Stats::default(),
+        });
+        state.stats.record(activity);
+
+        store_summary(hook_id, state)?;
+        Ok(())
+    }
+
+    pub async fn delete(&mut self, hook_id: &HookId) -> Result<()> {
+        if self.batches.remove(hook_id).is_some() {
+            delete_batch(hook_id)?;
+        }
+        if self.summaries.remove(hook_id).is_some() {
+            delete_summary(hook_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Takes the digests and summaries that are due, rendered for their hooks.
+    async fn take_due(&mut self, hooks: &[HookConfig], now: DateTime<Utc>) -> Vec<Due> {
+        let mut due = Vec::new();
+
+        for hook in hooks {
+            let batch_due = match self.batches.get(&hook.id) {
+                // Dropped right away, the events are marked as ignored
+                Some(_) if hook.disabled.is_some() => true,
+                Some(Batch { opens: Some(opens), .. }) => now >= *opens,
+                Some(batch) => batch.entries.len() >= hook.digest.max_events
+                    || now >= batch.started + chrono::Duration::seconds(hook.digest.window as i64),
+                None => false,
+            };
+
+            if let (true, Some(batch)) = (batch_due, self.batches.remove(&hook.id)) {
+                if let Err(err) = delete_batch(&hook.id) {
+                    log::error!("Failed to delete digest of hook {}: {}", hook.id, err);
+                }
+
+                let records = batch.entries.iter().map(|entry| entry.record_id).collect();
+                due.push(Due { hook: hook.clone(), embeds: digest_embeds(batch.entries), records });
+            }
+
+            // Summaries of disabled hooks keep counting until they are enabled again
+            if let (Some(period), None) = (hook.summary.period, &hook.disabled) {
+                let state = self.summaries.entry(hook.id.clone()).or_insert_with(|| SummaryState {
+                    period_started: now,
+                    stats: Stats::default(),
+                });
+
+                if now >= hook.summary.next_due(period, state.period_started) {
+                    let finished = std::mem::replace(state, SummaryState {
+                        period_started: now,
+                        stats: Stats::default(),
+                    });
+
+                    if let Err(err) = store_summary(&hook.id, state) {
+                        log::error!("Failed to store summary of hook {}: {}", hook.id, err);
+                    }
+                    if !finished.stats.is_empty() {
+                        let embeds = vec![summary_embed(hook, period, &finished)];
+                        due.push(Due { hook: hook.clone(), embeds, records: Vec::new() });
+                    }
+                }
+            }
+        }
+
+        // Batches of deleted hooks are never sent
+        let deleted = self.batches.keys()
+            .filter(|id| !hooks.iter().any(|hook| &hook.id == *id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for id in deleted {
+            self.batches.remove(&id);
+            if let Err(err) = delete_batch(&id) {
+                log::error!("Failed to delete digest of hook {}: {}", id, err);
+            }
+        }
+
+        due
+    }
+}
+
+/// Sends digests and summaries once they are due.
+pub async fn run(hooks: HookRegistry, digests: Digests, deliveries: DeliveryLog, queues: DeliveryQueues, breaker: CircuitBreaker, client: HttpClient, limiter: OutboundLimiter) {
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let all = hooks.read().await.all().await;
+        let due = digests.lock().await.take_due(&all, Utc::now()).await;
+
+        for due in due {
+            // Queued behind the events already on their way to the webhook
+            let destination = due.hook.discord_url.clone();
+            let delivery = send_due(hooks.clone(), deliveries.clone(), breaker.clone(), client.clone(), limiter.clone(), due);
+            queues.lock().await.enqueue(&destination, delivery);
+        }
+    }
+}
+
+async fn send_due(hooks: HookRegistry, deliveries: DeliveryLog, breaker: CircuitBreaker, client: HttpClient, limiter: OutboundLimiter, due: Due) {
+    let Due { hook, embeds, records } = due;
+    // The hook may have been disabled by the deliveries queued before this one
+    let disabled = hooks.read().await.get(&*hook.id.to_string()).await.ok().and_then(|config| config.disabled);
+
+    let (status, detail) = match disabled {
+        // Digests collected for disabled hooks are dropped with them
+        Some(disabled) => (DeliveryStatus::Ignored, Some(format!("Hook is disabled: {}", disabled.reason))),
+        None => {
+            let result = post_embeds(&client, &limiter, &hook, embeds).await;
+            let opened = record_outcome(&breaker, &hook, &result).await;
+
+            match result {
+                Ok(()) => (DeliveryStatus::Delivered, None),
+                Err(err) => {
+                    let err = EventError::from(err);
+                    log::error!("Failed to send digest for hook {}: {}", hook.id, err);
+                    if let Some(failures) = opened {
+                        let reason = format!("{} deliveries in a row failed, last with {}", failures, err);
+                        disable_destination(&hooks, &breaker, &client, &limiter, &hook, reason).await;
+                    }
+                    (DeliveryStatus::Failed, Some(err.to_string()))
+                },
+            }
+        },
+    };
+
+    let mut deliveries = deliveries.lock().await;
+    for record_id in records {
+        if let Err(err) = deliveries.update(&hook.id, record_id, status, detail.clone()).await {
+            log::error!("Failed to update delivery history: {}", err);
+        }
+    }
+}
+
+/// One embed per project, listing its events grouped by type.
+fn digest_embeds(entries: Vec<Entry>) -> Vec<Embed> {
+    let mut projects = Vec::<(Footer, Vec<Entry>)>::new();
+
+    for entry in entries {
+        match projects.iter_mut().find(|(footer, _)| footer.text == entry.footer.text) {
+            Some((_, project_entries)) => project_entries.push(entry),
+            None => projects.push((entry.footer.clone(), vec![entry])),
+        }
+    }
+
+    projects.into_iter()
+        .map(|(footer, entries)| {
+            let mut embed = Embed::new(
+                Title::new(format!("{} - {} events", footer.text, entries.len())),
+                Color::INFO,
+                footer,
+            );
+
+            let mut events = Vec::<(&str, Vec<String>)>::new();
+            for entry in &entries {
+                match events.iter_mut().find(|(event, _)| *event == entry.event) {
+                    Some((_, lines)) => lines.push(entry.line.clone()),
+                    None => events.push((&entry.event, vec![entry.line.clone()])),
+                }
+            }
+
+            for (event, lines) in events {
+                embed.append_section(event.trim_end_matches(" Hook"), &lines);
+            }
+
+            embed
+        })
+        .collect()
+}
+
+fn summary_embed(hook: &HookConfig, period: SummaryPeriod, state: &SummaryState) -> Embed {
+    let heading = match period {
+        SummaryPeriod::Daily => "Daily summary",
+        SummaryPeriod::Weekly => "Weekly summary",
+    };
+    let title = if hook.description.is_empty() {
+        heading.to_string()
+    } else {
+        format!("{} - {}", hook.description, heading)
+    };
+
+    let mut embed = Embed::new(
+        Title::new(title),
+        Color::INFO,
+        Footer::new(format!("Since {}", state.period_started.format("%Y-%m-%d %H:%M UTC")), String::new()),
+    );
+    embed.description = Some(state.stats.lines().join("\n"));
+    embed
+}
+
+fn store_batch(id: &HookId, batch: &Batch) -> io::Result<()> {
+    let json = serde_json::to_string(batch)?;
+    let outfile = get_batches_path()?.join(format!("{}.json", id));
+    fs::write(outfile, json)
+}
+
+fn delete_batch(id: &HookId) -> io::Result<()> {
+    let path = get_batches_path()?.join(format!("{}.json", id));
+    log::debug!("Deleting digest at {}", path.display());
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn load_all_batches() -> io::Result<HashMap<HookId, Batch>> {
+    let mut all = HashMap::new();
+    let path = get_batches_path()?;
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let id = path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| HookId::try_from(stem).ok());
+
+        if let (true, Some(id)) = (path.is_file(), id) {
+            log::debug!("Loading digest from {}", path.display());
+            let batch = serde_json::from_reader(File::open(&path)?)?;
+            all.insert(id, batch);
+        }
+    }
+
+    Ok(all)
+}
+
+fn get_batches_path() -> io::Result<PathBuf> {
+    let path = PathBuf::from(STORAGE_ROOT)
+        .join("digests");
+    fs::create_dir_all(&path)?;
+    Ok(path)
+}
+
+fn store_summary(id: &HookId, state: &SummaryState) -> io::Result<()> {
+    let json = serde_json::to_string(state)?;
+    let outfile = get_summaries_path()?.join(format!("{}.json", id));
+    fs::write(outfile, json)
+}
+
+fn delete_summary(id: &HookId) -> io::Result<()> {
+    let path = get_summaries_path()?.join(format!("{}.json", id));
+    log::debug!("Deleting summary at {}", path.display());
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn load_all_summaries() -> io::Result<HashMap<HookId, SummaryState>> {
+    let mut all = HashMap::new();
+    let path = get_summaries_path()?;
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let id = path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| HookId::try_from(stem).ok());
+
+        if let (true, Some(id)) = (path.is_file(), id) {
+            log::debug!("Loading summary from {}", path.display());
+            let state = serde_json::from_reader(File::open(&path)?)?;
+            all.insert(id, state);
+        }
+    }
+
+    Ok(all)
+}
+
+fn get_summaries_path() -> io::Result<PathBuf> {
+    let path = PathBuf::from(STORAGE_ROOT)
+        .join("summaries");
+    fs::create_dir_all(&path)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        let naive = chrono::NaiveDate::from_ymd_opt(2021, 3, day).unwrap()
+            .and_hms_opt(hour, minute, 0).unwrap();
+        Utc.from_utc_datetime(&naive)
+    }
+
+    #[test]
+    fn summaries_are_due_at_the_configured_hour() {
+        let config = SummaryConfig { period: Some(SummaryPeriod::Daily), hour: 9 };
+        // A Wednesday
+        let since = utc(10, 12, 30);
+
+        assert_eq!(config.next_due(SummaryPeriod::Daily, since), utc(11, 9, 0));
+        assert_eq!(config.next_due(SummaryPeriod::Daily, utc(10, 8, 0)), utc(10, 9, 0));
+        assert_eq!(config.next_due(SummaryPeriod::Weekly, since), utc(15, 9, 0));
+    }
+}
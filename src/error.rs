@@ -0,0 +1,124 @@
+use std::fmt::{self, Display};
+use crate::http::{StatusCode, Problem};
+
+/// Errors raised while turning an incoming event into a delivered message.
+#[derive(Debug)]
+pub enum EventError {
+    /// The payload could not be read or deserialized
+    Parse(Problem),
+    /// The event could not be rendered into a message
+    Render(RenderError),
+    /// The message could not be delivered to the destination
+    Delivery(DeliveryError),
+}
+
+impl EventError {
+    pub fn stage(&self) -> &'static str {
+        match self {
+            Self::Parse(_) => "parse",
+            Self::Render(_) => "render",
+            Self::Delivery(_) => "delivery",
+        }
+    }
+
+    pub fn into_problem(self) -> Problem {
+        match self {
+            Self::Parse(problem) => problem,
+            Self::Render(err) => {
+                Problem::new(StatusCode::UNPROCESSABLE_ENTITY, "Failed to render event")
+                    .with_detail(err)
+            },
+            Self::Delivery(err) => {
+                Problem::new(StatusCode::BAD_GATEWAY, "Failed to deliver event")
+                    .with_detail(err)
+            },
+        }
+    }
+}
+
+impl Display for EventError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(problem) => {
+                write!(fmt, "{} failed: {}", self.stage(), problem.title)?;
+                if let Some(detail) = &problem.detail {
+                    write!(fmt, " ({})", detail)?;
+                }
+                Ok(())
+            },
+            Self::Render(err) => write!(fmt, "{} failed: {}", self.stage(), err),
+            Self::Delivery(err) => write!(fmt, "{} failed: {}", self.stage(), err),
+        }
+    }
+}
+
+impl std::error::Error for EventError {}
+
+impl From<Problem> for EventError {
+    fn from(problem: Problem) -> Self {
+        Self::Parse(problem)
+    }
+}
+
+impl From<RenderError> for EventError {
+    fn from(err: RenderError) -> Self {
+        Self::Render(err)
+    }
+}
+
+impl From<DeliveryError> for EventError {
+    fn from(err: DeliveryError) -> Self {
+        Self::Delivery(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    InvalidTimestamp {
+        field: &'static str,
+        value: String,
+    },
+}
+
+impl Display for RenderError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidTimestamp { field, value } => {
+                write!(fmt, "invalid timestamp {:?} in {}", value, field)
+            },
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// The message could not be turned into a request
+    InvalidMessage(String),
+    /// The request did not produce a response
+    Request(String),
+    /// The destination rejected the message
+    Rejected {
+        status: StatusCode,
+        body: String,
+    },
+    /// A rate limit response did not tell us how long to wait
+    InvalidRateLimit(String),
+    /// The destination accepted the message, but its response was unusable
+    InvalidResponse(String),
+}
+
+impl Display for DeliveryError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMessage(msg) => write!(fmt, "invalid message: {}", msg),
+            Self::Request(msg) => write!(fmt, "request failed: {}", msg),
+            Self::Rejected { status, body } => write!(fmt, "destination responded with {}: {}", status, body),
+            Self::InvalidRateLimit(msg) => write!(fmt, "invalid rate limit response: {}", msg),
+            Self::InvalidResponse(msg) => write!(fmt, "invalid response: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
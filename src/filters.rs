@@ -0,0 +1,153 @@
+use serde::{Serialize, Deserialize};
+use crate::models::gitlab::{NoteEvent, JobEvent, MergeRequestEvent};
+
+/// Per hook settings deciding which events get forwarded.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EventFilters {
+    pub issues: IssueFilter,
+    pub merge_requests: MergeRequestFilter,
+    pub notes: NoteFilter,
+    pub jobs: JobFilter,
+    pub pipelines: PipelineFilter,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IssueFilter {
+    /// Any of `open`, `close`, `reopen` and `update`
+    pub actions: Vec<String>,
+}
+
+impl Default for IssueFilter {
+    fn default() -> Self {
+        Self {
+            actions: vec!["open".into(), "close".into()],
+        }
+    }
+}
+
+impl IssueFilter {
+    pub fn allows(&self, action: &str) -> bool {
+        self.actions.iter().any(|a| a == action)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MergeRequestFilter {
+    /// Any of `open`, `close`, `reopen`, `update`, `ready`, `approved`,
+    /// `unapproved`, `approval`, `unapproval` and `merge`
+    pub actions: Vec<String>,
+}
+
+impl Default for MergeRequestFilter {
+    fn default() -> Self {
+        Self {
+            actions: vec!["open".into(), "close".into(), "merge".into()],
+        }
+    }
+}
+
+impl MergeRequestFilter {
+    /// Ready for review is a special kind of update, so it is also forwarded
+    /// when updates are enabled.
+    pub fn matches(&self, event: &MergeRequestEvent) -> bool {
+        let allows = |action: &str| self.actions.iter().any(|a| a == action);
+        let action = event.action();
+
+        allows(action) || (action == "ready" && allows("update"))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoteFilter {
+    pub enabled: bool,
+    /// Skip notes generated by GitLab itself, like "changed the description"
+    pub skip_system: bool,
+    /// Only forward notes mentioning at least one of these, e.g. `@team`
+    pub mentions: Vec<String>,
+}
+
+impl Default for NoteFilter {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            skip_system: true,
+            mentions: Vec::new(),
+        }
+    }
+}
+
+impl NoteFilter {
+    pub fn matches(&self, event: &NoteEvent) -> bool {
+        if !self.enabled || (self.skip_system && event.attributes.system) {
+            return false;
+        }
+
+        self.mentions.is_empty()
+            || self.mentions.iter().any(|mention| mentions(&event.attributes.note, mention))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobFilter {
+    pub enabled: bool,
+    /// Skip failed jobs that are allowed to fail
+    pub skip_allowed_failures: bool,
+    /// Hold back failed jobs and report them with the pipeline instead
+    pub combine_with_pipeline: bool,
+}
+
+impl Default for JobFilter {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            skip_allowed_failures: false,
+            combine_with_pipeline: false,
+        }
+    }
+}
+
+impl JobFilter {
+    pub fn matches(&self, event: &JobEvent) -> bool {
+        self.enabled
+            && event.build_status == "failed"
+            && !(self.skip_allowed_failures && event.build_allow_failure)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PipelineFilter {
+    /// Post a message when a pipeline starts and edit it as the pipeline runs,
+    /// instead of only reporting the final result
+    pub live_updates: bool,
+}
+
+/// Checks for a mention, making sure `@team` does not match `@teammate`.
+fn mentions(text: &str, mention: &str) -> bool {
+    let mention = mention.trim_start_matches('@').to_lowercase();
+    let text = text.to_lowercase();
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.';
+
+    text.match_indices('@').any(|(pos, _)| {
+        let name = text[pos + 1..].split(|c: char| !is_name_char(c)).next().unwrap_or_default();
+        name.trim_end_matches('.') == mention
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mention_matching() {
+        assert!(mentions("ping @Team please", "@team"));
+        assert!(mentions("thanks @team.", "team"));
+        assert!(!mentions("ping @teammate", "@team"));
+        assert!(!mentions("email team@example.com", "@team"));
+    }
+}
@@ -1,13 +1,16 @@
 use std::convert::TryInto;
 use hyper_rustls::HttpsConnector;
-use hyper::client::HttpConnector;
-pub use hyper::http::StatusCode;
+use hyper::{client::HttpConnector, body::HttpBody};
+pub use hyper::http::{StatusCode, Method, HeaderMap};
 use anyhow::anyhow;
+use serde::{Serialize, de::DeserializeOwned};
+use bytes::{Bytes, BytesMut, BufMut};
 use crate::Result;
 
 pub type Request<T = hyper::Body> = hyper::Request<T>;
 pub type Response<T = hyper::Body> = hyper::Response<T>;
 
+#[derive(Clone)]
 pub struct Client(hyper::Client<HttpsConnector<HttpConnector>>);
 
 impl Client {
@@ -17,13 +20,13 @@ impl Client {
         Self(client)
     }
 
-    pub async fn post<U, B>(&self, uri: U, body: B) -> Result<Response>
+    pub async fn request<U, B>(&self, method: Method, uri: U, body: B) -> Result<Response>
     where U: TryInto<hyper::Uri>,
           B: Into<hyper::Body>
     {
         let uri = uri.try_into().or_else(|_| Err(anyhow!("Failed to parse URI")))?;
         let request = hyper::Request::builder()
-            .method("POST")
+            .method(method)
             .uri(uri)
             .header("Content-Type","application/json")
             .body(body.into())?;
@@ -32,3 +35,152 @@ impl Client {
     }
 }
 
+
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimits {
+    pub hooks: usize,
+    pub api: usize,
+}
+
+/// A JSON problem details response, see RFC 7807.
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    #[serde(serialize_with = "serialize_status")]
+    pub status: StatusCode,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// JSON pointer to the offending field of the request payload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pointer: Option<String>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, title: &str) -> Self {
+        Self {
+            status,
+            title: title.to_string(),
+            detail: None,
+            pointer: None,
+        }
+    }
+
+    pub fn with_detail<D: ToString>(mut self, detail: D) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    pub fn with_pointer<P: Into<String>>(mut self, pointer: P) -> Self {
+        self.pointer = Some(pointer.into());
+        self
+    }
+
+    pub fn invalid_field(pointer: &str, detail: &str) -> Self {
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, "Invalid field")
+            .with_detail(detail)
+            .with_pointer(pointer)
+    }
+
+    pub fn into_response(self) -> Result<Response> {
+        let json = serde_json::to_string(&self)?;
+
+        Ok(Response::builder()
+            .status(self.status)
+            .header("Content-Type", "application/problem+json")
+            .body(json.into())?)
+    }
+}
+
+fn serialize_status<S: serde::Serializer>(status: &StatusCode, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_u16(status.as_u16())
+}
+
+/// Reads the whole request body, rejecting it as soon as it exceeds `limit` bytes.
+pub async fn read_body(req: &mut Request, limit: usize) -> std::result::Result<Bytes, Problem> {
+    let too_large = || {
+        Problem::new(StatusCode::PAYLOAD_TOO_LARGE, "Payload too large")
+            .with_detail(format!("Request body exceeds {} bytes", limit))
+    };
+
+    let content_length = req.headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+
+    if let Some(length) = content_length {
+        if length > limit {
+            return Err(too_large());
+        }
+    }
+
+    let body = req.body_mut();
+    let mut buf = BytesMut::with_capacity(content_length.unwrap_or(0));
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            Problem::new(StatusCode::BAD_REQUEST, "Failed to read request body").with_detail(e)
+        })?;
+
+        if buf.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+
+        buf.put(chunk);
+    }
+
+    Ok(buf.freeze())
+}
+
+/// Deserializes a JSON payload, reporting syntax errors as `400` and
+/// mismatching data as `422` along with the path of the invalid field.
+pub fn parse_json<T: DeserializeOwned>(body: &[u8]) -> std::result::Result<T, Problem> {
+    use serde_json::error::Category;
+    use serde_path_to_error::Segment;
+
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|err| {
+        let pointer = err.path().iter()
+            .map(|segment| match segment {
+                Segment::Seq { index } => format!("/{}", index),
+                Segment::Map { key } => format!("/{}", key.replace('~', "~0").replace('/', "~1")),
+                Segment::Enum { variant } => format!("/{}", variant),
+                Segment::Unknown => "/?".to_string(),
+            })
+            .collect::<String>();
+        let inner = err.into_inner();
+
+        match inner.classify() {
+            Category::Data => {
+                Problem::new(StatusCode::UNPROCESSABLE_ENTITY, "Invalid payload")
+                    .with_detail(inner)
+                    .with_pointer(pointer)
+            },
+            _ => Problem::new(StatusCode::BAD_REQUEST, "Malformed JSON").with_detail(inner),
+        }
+    })?;
+
+    deserializer.end()
+        .map_err(|err| Problem::new(StatusCode::BAD_REQUEST, "Malformed JSON").with_detail(err))?;
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parse_json_rejects_trailing_data() {
+        let parsed = parse_json::<HashMap<String, u32>>(br#"{"a": 1} "#).unwrap();
+        assert_eq!(parsed["a"], 1);
+
+        let problem = parse_json::<HashMap<String, u32>>(br#"{"a": 1} garbage"#).unwrap_err();
+        assert_eq!(problem.status, StatusCode::BAD_REQUEST);
+
+        let problem = parse_json::<HashMap<String, u32>>(br#"{"a": "one"}"#).unwrap_err();
+        assert_eq!(problem.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem.pointer.as_deref(), Some("/a"));
+    }
+}
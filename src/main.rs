@@ -5,15 +5,33 @@ mod http;
 mod routes;
 mod models;
 mod store;
-
-use std::{net::SocketAddr, collections::HashMap};
-use tokio::sync::RwLock;
+mod ratelimit;
+mod access;
+mod error;
+mod filters;
+mod digest;
+mod schedule;
+mod outbound;
+mod queue;
+mod breaker;
+mod samples;
+
+use std::{net::SocketAddr, collections::HashMap, time::Duration, sync::Arc};
+use tokio::sync::{RwLock, Mutex};
 use routerify::{Router, RouterService};
 pub use anyhow::Result;
 
 pub type UserMap = HashMap<String, String>;
 pub type Users = RwLock<UserMap>;
-pub type HookRegistry = RwLock<store::HookRegistry>;
+pub type HookRegistry = Arc<RwLock<store::HookRegistry>>;
+pub type RateLimiter = Mutex<ratelimit::RateLimiter>;
+pub type DeliveryLog = Arc<Mutex<store::DeliveryLog>>;
+pub type JobCache = Mutex<store::JobCache>;
+pub type MessageIndex = Mutex<store::MessageIndex>;
+pub type Digests = Arc<Mutex<digest::DigestStore>>;
+pub type OutboundLimiter = Arc<outbound::OutboundLimiter>;
+pub type DeliveryQueues = Arc<Mutex<queue::DeliveryQueues>>;
+pub type CircuitBreaker = Arc<Mutex<breaker::CircuitBreaker>>;
 
 #[derive(Debug, argh::FromArgs)]
 /// GitLab to Discord webhook server
@@ -22,9 +40,57 @@ struct AppArgs {
     /// an colon separated pair of user and hashed password
     user: Vec<(String, String)>,
 
+    #[argh(option, from_str_fn(access::parse_ip_net))]
+    /// an IP address or CIDR range allowed to call hooks without an allowlist of their own
+    allow_ip: Vec<ipnet::IpNet>,
+
+    #[argh(option, from_str_fn(access::parse_ip_net))]
+    /// an IP address or CIDR range of a reverse proxy whose forwarding headers are trusted
+    trusted_proxy: Vec<ipnet::IpNet>,
+
+    #[argh(option, default = "5 * 1024 * 1024")]
+    /// maximum size of a webhook request body in bytes
+    max_hook_body_size: usize,
+
+    #[argh(option, default = "64 * 1024")]
+    /// maximum size of an API request body in bytes
+    max_api_body_size: usize,
+
+    #[argh(option, default = "24 * 60 * 60")]
+    /// how long event ids are remembered to detect redeliveries, in seconds
+    dedup_window: i64,
+
     #[argh(switch)]
     /// enables debug logging
     debug: bool,
+
+    #[argh(option, default = "60")]
+    /// length of the rate limiting window in seconds
+    rate_limit_window: u64,
+
+    #[argh(option, default = "120")]
+    /// maximum number of requests per client IP within a window
+    ip_rate_limit: u32,
+
+    #[argh(option, default = "300")]
+    /// maximum number of requests per hook within a window
+    hook_rate_limit: u32,
+
+    #[argh(option, default = "5")]
+    /// failed authentication attempts within a window before locking out a client
+    max_auth_failures: u32,
+
+    #[argh(option, default = "900")]
+    /// how long a client stays locked out, in seconds
+    lockout_duration: u64,
+
+    #[argh(option, default = "10")]
+    /// failed deliveries in a row before a hook is disabled, 0 never disables hooks
+    failure_threshold: u32,
+
+    #[argh(option)]
+    /// a Discord webhook URL notified when hooks are disabled
+    admin_webhook: Option<String>,
 }
 
 fn parse_user(value: &str) -> Result<(String, String), String> {
@@ -50,15 +116,54 @@ async fn main() -> Result<()> {
 
     let users = args.user.into_iter().collect::<UserMap>();
     let client = http::Client::new();
-    let hooks = store::HookRegistry::load()?;
+    let hooks = Arc::new(RwLock::new(store::HookRegistry::load()?));
+    let digests = Arc::new(Mutex::new(digest::DigestStore::load()?));
+    let outbound_limiter = Arc::new(outbound::OutboundLimiter::default());
+    let deliveries = Arc::new(Mutex::new(store::DeliveryLog::load(chrono::Duration::seconds(args.dedup_window))?));
+    let messages = store::MessageIndex::load()?;
+    let access = access::AccessConfig {
+        default_allowed: args.allow_ip,
+        trusted_proxies: args.trusted_proxy,
+    };
+    let body_limits = http::BodyLimits {
+        hooks: args.max_hook_body_size,
+        api: args.max_api_body_size,
+    };
+    let queues = DeliveryQueues::default();
+    let breaker = Arc::new(Mutex::new(breaker::CircuitBreaker::new(args.failure_threshold, args.admin_webhook)));
+    let rate_limiter = ratelimit::RateLimiter::new(ratelimit::RateLimitConfig {
+        window: Duration::from_secs(args.rate_limit_window),
+        ip_limit: args.ip_rate_limit,
+        hook_limit: args.hook_rate_limit,
+        max_failures: args.max_auth_failures,
+        lockout: Duration::from_secs(args.lockout_duration),
+    });
 
     let router = Router::builder()
         .data(RwLock::new(users))
-        .data(RwLock::new(hooks))
-        .data(client)
+        .data(hooks.clone())
+        .data(digests.clone())
+        .data(deliveries.clone())
+        .data(Mutex::new(store::JobCache::default()))
+        .data(Mutex::new(messages))
+        .data(Mutex::new(rate_limiter))
+        .data(access)
+        .data(body_limits)
+        .data(client.clone())
+        .data(outbound_limiter.clone())
+        .data(queues.clone())
+        .data(breaker.clone())
         .get("/api/hooks", routes::api::get_hooks)
+        .get("/api/ratelimits", routes::api::get_rate_limits)
         .post("/api/hook", routes::api::post_hook)
         .delete("/api/hook/:id", routes::api::delete_hook)
+        .post("/api/hook/:id/enable", routes::api::enable_hook)
+        .post("/api/hook/:id/test", routes::api::test_hook)
+        .post("/api/render", routes::api::render)
+        .get("/api/hook/:id/deliveries", routes::api::get_deliveries)
+        .get("/api/mentions", routes::api::get_user_mappings)
+        .post("/api/mention", routes::api::post_user_mapping)
+        .delete("/api/mention/:gitlab", routes::api::delete_user_mapping)
         .post("/hooks/gitlab/:id", routes::hooks::post_gitlab)
         .build()?;
 
@@ -66,6 +171,8 @@ async fn main() -> Result<()> {
     let service = RouterService::new(router)?;
     let server = Server::try_bind(&addr)?.serve(service);
 
+    tokio::spawn(digest::run(hooks, digests, deliveries, queues, breaker, client, outbound_limiter));
+
     log::info!("Starting server on http://{}", addr);
     server.await?;
 
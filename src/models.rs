@@ -1,14 +1,177 @@
 pub mod discord;
 pub mod gitlab;
 
-use serde::Deserialize;
-use crate::store::{HookConfig, HookId};
+use serde::{Serialize, Deserialize};
+use crate::{
+    store::{HookConfig, HookId, DuplicatePolicy},
+    http::Problem,
+    filters::EventFilters,
+    digest::{DigestConfig, SummaryConfig},
+    schedule::Schedule,
+    samples,
+};
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct CreateHookConfig {
     pub description: String,
     pub gitlab_token: String,
     pub discord_url: String,
+    #[serde(default)]
+    pub allowed_ips: Vec<ipnet::IpNet>,
+    #[serde(default)]
+    pub duplicates: DuplicatePolicy,
+    #[serde(default)]
+    pub filters: EventFilters,
+    #[serde(default)]
+    pub threads: bool,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    #[serde(default)]
+    pub digest: DigestConfig,
+    #[serde(default)]
+    pub summary: SummaryConfig,
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+}
+
+impl CreateHookConfig {
+    pub fn validate(&self) -> Result<(), Problem> {
+        if self.gitlab_token.is_empty() {
+            return Err(Problem::invalid_field("/gitlab_token", "Token must not be empty"));
+        }
+
+        if !is_https_url(&self.discord_url) {
+            return Err(Problem::invalid_field("/discord_url", "Must be an absolute https URL"));
+        }
+
+        if let Some(username) = &self.username {
+            let length = username.trim().chars().count();
+            if length == 0 || length > USERNAME_MAX_LENGTH {
+                return Err(Problem::invalid_field("/username", "Must be between 1 and 80 characters"));
+            }
+        }
+
+        if let Some(avatar_url) = &self.avatar_url {
+            if !is_https_url(avatar_url) {
+                return Err(Problem::invalid_field("/avatar_url", "Must be an absolute https URL"));
+            }
+        }
+
+        if self.digest.max_events == 0 {
+            return Err(Problem::invalid_field("/digest/max_events", "Must be at least 1"));
+        }
+
+        if self.summary.hour > 23 {
+            return Err(Problem::invalid_field("/summary/hour", "Must be an hour between 0 and 23"));
+        }
+
+        if let Some(schedule) = &self.schedule {
+            if schedule.days.is_empty() {
+                return Err(Problem::invalid_field("/schedule/days", "Must contain at least one day"));
+            }
+
+            if schedule.start > 23 || schedule.end > 23 {
+                let pointer = if schedule.start > 23 { "/schedule/start" } else { "/schedule/end" };
+                return Err(Problem::invalid_field(pointer, "Must be an hour between 0 and 23"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A sample event sent through a hook by `POST /api/hook/:id/test`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TestEvent {
+    /// GitLab event name, e.g. `Push Hook`
+    pub event: String,
+    /// The built-in sample of the event is used when left out
+    #[serde(default)]
+    pub payload: Option<serde_json::Value>,
+}
+
+impl TestEvent {
+    pub fn payload(&self) -> Result<Vec<u8>, Problem> {
+        sample_payload(&self.event, self.payload.as_ref())
+    }
+}
+
+/// An event rendered without delivering it by `POST /api/render`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RenderRequest {
+    /// Only `gitlab` for now
+    pub source: String,
+    pub event: String,
+    #[serde(default)]
+    pub payload: Option<serde_json::Value>,
+    /// Renders with the filters and appearance of this hook instead of the defaults
+    #[serde(default)]
+    pub hook: Option<HookId>,
+}
+
+impl RenderRequest {
+    pub fn payload(&self) -> Result<Vec<u8>, Problem> {
+        if self.source != "gitlab" {
+            return Err(Problem::invalid_field("/source", "Only gitlab is supported"));
+        }
+
+        sample_payload(&self.event, self.payload.as_ref())
+    }
+}
+
+fn sample_payload(event: &str, payload: Option<&serde_json::Value>) -> Result<Vec<u8>, Problem> {
+    match payload {
+        Some(payload) => Ok(serde_json::to_vec(payload).expect("JSON values serialize")),
+        None => samples::gitlab(event)
+            .map(|sample| sample.as_bytes().to_vec())
+            .ok_or_else(|| Problem::invalid_field("/payload", "No sample for this event, a payload is required")),
+    }
+}
+
+/// Longest name Discord accepts for webhook messages
+const USERNAME_MAX_LENGTH: usize = 80;
+
+fn is_https_url(value: &str) -> bool {
+    matches!(value.parse::<hyper::Uri>(), Ok(uri) if uri.scheme_str() == Some("https") && uri.host().is_some())
+}
+
+/// Who to mention on Discord for a GitLab user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserMapping {
+    /// GitLab username or commit email
+    pub gitlab: String,
+    #[serde(flatten)]
+    pub discord: DiscordTarget,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscordTarget {
+    /// Id of a Discord user
+    User(String),
+    /// Id of a Discord role
+    Role(String),
+}
+
+impl UserMapping {
+    pub fn validate(&self) -> Result<(), Problem> {
+        if self.gitlab.trim().is_empty() {
+            return Err(Problem::invalid_field("/gitlab", "Must not be empty"));
+        }
+
+        let (pointer, id) = match &self.discord {
+            DiscordTarget::User(id) => ("/user", id),
+            DiscordTarget::Role(id) => ("/role", id),
+        };
+
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+            return Err(Problem::invalid_field(pointer, "Must be a Discord id"));
+        }
+
+        Ok(())
+    }
 }
 
 impl Into<HookConfig> for CreateHookConfig {
@@ -18,6 +181,16 @@ impl Into<HookConfig> for CreateHookConfig {
             description: self.description,
             gitlab_token: self.gitlab_token,
             discord_url: self.discord_url,
+            allowed_ips: self.allowed_ips,
+            duplicates: self.duplicates,
+            filters: self.filters,
+            threads: self.threads,
+            username: self.username,
+            avatar_url: self.avatar_url,
+            digest: self.digest,
+            summary: self.summary,
+            schedule: self.schedule,
+            disabled: None,
             created_at: chrono::Utc::now(),
         }
     }
@@ -1,6 +1,9 @@
-use serde::{Serialize, Serializer};
+use std::convert::TryFrom;
+use serde::{Serialize, Serializer, Deserialize};
 use chrono::DateTime;
-use super::gitlab;
+use unicode_segmentation::UnicodeSegmentation;
+use crate::error::RenderError;
+use super::{gitlab, DiscordTarget};
 
 macro_rules! build_project_title {
     [ $project:expr, $fmt:literal, $($args:expr),*] => {{
@@ -9,7 +12,7 @@ macro_rules! build_project_title {
     }}
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Color(u8, u8, u8);
 
 impl Color {
@@ -23,18 +26,19 @@ impl Serialize for Color {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: Serializer
     {
-        let value: u32 = 0x00_00_00_00
-            | ((self.0 as u32) << 16)
+        let value: u32 = ((self.0 as u32) << 16)
             | ((self.1 as u32) << 8)
             | (self.2 as u32);
         serializer.serialize_u32(value)
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Embed {
-    pub author: Author,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<Author>,
     pub title: Title,
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub url: String,
     pub color: Color,
     pub footer: Footer,
@@ -42,13 +46,254 @@ pub struct Embed {
     pub timestamp: Option<DateTime<chrono::FixedOffset>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<Field>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<Image>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<Image>,
 }
 
 impl Embed {
     pub const DESCRIPTION_MAX_LENGTH: usize = 2048;
+    pub const MAX_FIELDS: usize = 25;
+
+    /// An embed with nothing but a title, for messages not rendered from a single event.
+    pub fn new(title: Title, color: Color, footer: Footer) -> Self {
+        Self {
+            author: None,
+            title,
+            url: String::new(),
+            color,
+            footer,
+            timestamp: None,
+            description: None,
+            fields: Vec::new(),
+            thumbnail: None,
+            image: None,
+        }
+    }
+
+    /// A single line linking to the embed, used to list it in a digest.
+    pub fn digest_line(&self) -> String {
+        // Titles start with the project name, which the digest already groups by
+        let title = match self.title.0.find(" - ") {
+            Some(index) => &self.title.0[index + " - ".len()..],
+            None => &self.title.0,
+        };
+        let mut line = format!("[{}]({})", escape_markdown(title), self.url);
+
+        if let Some(author) = &self.author {
+            line.push_str(&format!(" by {}", escape_markdown(&author.name)));
+        }
+
+        line
+    }
+
+    /// Number of characters counting towards the total length limit of a message.
+    pub fn text_length(&self) -> usize {
+        let author = self.author.as_ref().map_or(0, |author| author.name.chars().count());
+        let description = self.description.as_ref().map_or(0, |d| d.chars().count());
+        let fields = self.fields.iter()
+            .map(|field| field.name.chars().count() + field.value.chars().count())
+            .sum::<usize>();
+
+        author + self.title.0.chars().count() + description + self.footer.text.chars().count() + fields
+    }
+
+    /// Splits the embed into as many as needed to stay within the field and
+    /// length limits, moving the fields that don't fit into continuations.
+    pub fn split(mut self) -> Vec<Embed> {
+        let fields = std::mem::take(&mut self.fields);
+        let continuation = Embed {
+            author: None,
+            title: Title::new(format!("{} (continued)", self.title.0)),
+            url: self.url.clone(),
+            color: self.color,
+            footer: self.footer.clone(),
+            timestamp: self.timestamp,
+            description: None,
+            fields: Vec::new(),
+            thumbnail: None,
+            image: None,
+        };
+
+        let mut embeds = vec![self];
+        for field in fields {
+            let embed = embeds.last_mut().unwrap();
+            let fits = embed.fields.len() < Self::MAX_FIELDS
+                && embed.text_length() + field.name.chars().count() + field.value.chars().count() <= WebhookMessage::MAX_LENGTH;
+
+            if fits || embed.fields.is_empty() {
+                embed.fields.push(field);
+            } else {
+                let mut next = continuation.clone();
+                next.fields.push(field);
+                embeds.push(next);
+            }
+        }
+
+        embeds
+    }
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct Image {
+    pub url: String,
+}
+
+impl Image {
+    pub fn new(url: String) -> Self {
+        Self { url }
+    }
+}
+
+/// The payload of a webhook execution.
 #[derive(Debug, Serialize)]
+pub struct WebhookMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    /// Name of a new forum thread to post the message in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_name: Option<String>,
+    pub embeds: Vec<Embed>,
+    pub allowed_mentions: AllowedMentions,
+}
+
+/// Which mentions in a message may notify users, see [`AllowedMentions::none`].
+#[derive(Debug, Clone, Serialize)]
+pub struct AllowedMentions {
+    pub parse: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+}
+
+impl AllowedMentions {
+    /// Nothing in a message can ping anyone, whatever the payload contains.
+    pub fn none() -> Self {
+        Self {
+            parse: Vec::new(),
+            users: Vec::new(),
+            roles: Vec::new(),
+        }
+    }
+
+    /// Only the given users and roles can be pinged.
+    pub fn only(targets: &[DiscordTarget]) -> Self {
+        let mut allowed = Self::none();
+
+        for target in targets {
+            match target {
+                DiscordTarget::User(id) => allowed.users.push(id.clone()),
+                DiscordTarget::Role(id) => allowed.roles.push(id.clone()),
+            }
+        }
+
+        allowed
+    }
+}
+
+impl WebhookMessage {
+    pub const MAX_EMBEDS: usize = 10;
+    /// Total characters of all embeds in a message
+    pub const MAX_LENGTH: usize = 6000;
+
+    /// Groups embeds into as many messages as needed to stay within the limits.
+    pub fn split(embeds: Vec<Embed>, username: Option<String>, avatar_url: Option<String>) -> Vec<Self> {
+        let mut messages = Vec::<Self>::new();
+
+        for embed in embeds.into_iter().flat_map(Embed::split) {
+            let fits = matches!(messages.last(), Some(message) if message.embeds.len() < Self::MAX_EMBEDS
+                && message.embeds.iter().map(Embed::text_length).sum::<usize>() + embed.text_length() <= Self::MAX_LENGTH);
+
+            if fits {
+                messages.last_mut().unwrap().embeds.push(embed);
+            } else {
+                messages.push(Self {
+                    content: None,
+                    username: username.clone(),
+                    avatar_url: avatar_url.clone(),
+                    thread_name: None,
+                    embeds: vec![embed],
+                    allowed_mentions: AllowedMentions::none(),
+                });
+            }
+        }
+
+        messages
+    }
+
+    /// Mentions the targets in the content, allowing exactly them to be pinged.
+    pub fn mention(&mut self, targets: &[DiscordTarget]) {
+        if targets.is_empty() {
+            return;
+        }
+
+        let mentions = targets.iter()
+            .map(|target| match target {
+                DiscordTarget::User(id) => format!("<@{}>", id),
+                DiscordTarget::Role(id) => format!("<@&{}>", id),
+            })
+            .collect::<Vec<_>>();
+
+        self.content = Some(mentions.join(" "));
+        self.allowed_mentions = AllowedMentions::only(targets);
+    }
+}
+
+/// The message Discord returns for webhook executions with `wait=true`.
+#[derive(Debug, Deserialize)]
+pub struct Message {
+    pub id: String,
+    pub channel_id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Field {
+    pub name: String,
+    pub value: String,
+    pub inline: bool,
+}
+
+impl Field {
+    pub const NAME_MAX_LENGTH: usize = 256;
+    pub const VALUE_MAX_LENGTH: usize = 1024;
+
+    /// Builds a field from lines, replacing the ones that don't fit with a count.
+    pub fn from_lines(name: &str, lines: &[String], inline: bool) -> Self {
+        let mut value = String::new();
+
+        for (i, line) in lines.iter().enumerate() {
+            let remaining = lines.len() - i;
+            let more = format!("\n…and {} more", remaining);
+            let reserved = if remaining > 1 { more.chars().count() } else { 0 };
+
+            if value.chars().count() + line.chars().count() + 1 + reserved > Self::VALUE_MAX_LENGTH {
+                value.push_str(if value.is_empty() { more.trim_start() } else { &more });
+                break;
+            }
+
+            if !value.is_empty() {
+                value.push('\n');
+            }
+            value.push_str(line);
+        }
+
+        Self {
+            name: truncate(name, Self::NAME_MAX_LENGTH),
+            value,
+            inline,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Author {
     pub name: String,
     pub icon_url: String,
@@ -57,144 +302,1013 @@ pub struct Author {
 impl Author {
     pub const MAX_LENGTH: usize = 256;
 
-    pub fn new(mut name: String, icon_url: String) -> Self {
-        name.truncate(Self::MAX_LENGTH);
-        Self { name, icon_url }
+    pub fn new(name: String, icon_url: String) -> Self {
+        Self { name: truncate(&name, Self::MAX_LENGTH), icon_url }
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Footer {
     pub text: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub icon_url: String,
 }
 
 impl Footer {
     pub const MAX_LENGTH: usize = 2048;
 
-    pub fn new(mut text: String, icon_url: String) -> Self {
-        text.truncate(Self::MAX_LENGTH);
-        Self { text: text.into(), icon_url }
+    pub fn new(text: String, icon_url: String) -> Self {
+        Self { text: truncate(&text, Self::MAX_LENGTH), icon_url }
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(transparent)]
 pub struct Title(String);
 
 impl Title {
     pub const MAX_LENGTH: usize = 256;
 
-    pub fn new(mut text: String) -> Self {
-        text.truncate(Self::MAX_LENGTH);
-        Self(text)
+    pub fn new(text: String) -> Self {
+        Self(truncate(&text, Self::MAX_LENGTH))
     }
 }
 
-impl From<gitlab::PushEvent> for Embed {
-    fn from(ev: gitlab::PushEvent) -> Self {
-        Self {
-            author: Author::new(ev.username, ev.user_avatar),
+impl TryFrom<gitlab::PushEvent> for Embed {
+    type Error = RenderError;
+
+    fn try_from(ev: gitlab::PushEvent) -> Result<Self, Self::Error> {
+        let branch = ev.branch_name().to_string();
+        let web_url = &ev.project.web_url;
+        let compare_url = format!("{}/-/compare/{}...{}", web_url, ev.before, ev.after);
+        let commits = |more_url: &str| {
+            Some(join_commit_lines(&ev.commits, &ev.project, ev.total_commits_count, more_url)).filter(|lines| !lines.is_empty())
+        };
+
+        let (title, url, color, description) = if ev.is_deletion() {
+            let title = build_project_title!(&ev.project, "Branch {} deleted", branch);
+            (title, format!("{}/-/branches", web_url), Color::ALERT, None)
+        } else if ev.is_creation() {
+            let branch_url = format!("{}/-/tree/{}", web_url, branch);
+            let title = match ev.total_commits_count {
+                0 => build_project_title!(&ev.project, "Branch {} created", branch),
+                count => build_project_title!(&ev.project, "Branch {} created with {}", branch, plural(count, "new commit")),
+            };
+            (title, branch_url, Color::INFO, commits(&format!("{}/-/commits/{}", web_url, branch)))
+        } else if ev.is_force_push() {
+            let title = build_project_title!(&ev.project, "Force pushed to {}", branch);
+            let description = format!("[`{:.8}`]({}) → `{:.8}`", ev.before, compare_url, ev.after);
+            (title, compare_url, Color::ALERT, Some(description))
+        } else {
+            let title = build_project_title!(&ev.project, "{} in {}", plural(ev.total_commits_count, "new commit"), branch);
+            let description = commits(&compare_url);
+            (title, compare_url, Color::INFO, description)
+        };
+
+        Ok(Self {
+            author: Some(Author::new(ev.username, ev.user_avatar)),
+            title,
+            url,
+            color,
+            footer: Footer::new(ev.project.path_with_namespace, ev.project.avatar_url),
+            fields: Vec::new(),
+            thumbnail: None,
+            image: None,
+            timestamp: None,
+            description,
+        })
+    }
+}
+
+pub fn plural(count: u64, noun: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, noun)
+    } else {
+        format!("{} {}s", count, noun)
+    }
+}
+
+impl TryFrom<gitlab::TagPushEvent> for Embed {
+    type Error = RenderError;
+
+    fn try_from(ev: gitlab::TagPushEvent) -> Result<Self, Self::Error> {
+        let tag = ev.tag_name().to_string();
+        let deleted = ev.is_deletion();
+
+        Ok(Self {
+            author: Some(Author::new(ev.username, ev.user_avatar)),
             title: build_project_title!(
-                &ev.project, "{} new commits in {}", ev.total_commits_count, ev.git_ref.split('/').last().unwrap()
+                &ev.project, "Tag {} {}", tag, if deleted { "deleted" } else { "created" }
             ),
-            url: ev.project.web_url,
+            url: if deleted {
+                format!("{}/-/tags", ev.project.web_url)
+            } else {
+                format!("{}/-/tags/{}", ev.project.web_url, tag)
+            },
+            color: if deleted { Color::ALERT } else { Color::INFO },
+            footer: Footer::new(ev.project.path_with_namespace, ev.project.avatar_url),
+            fields: Vec::new(),
+            thumbnail: None,
+            image: None,
+            timestamp: None,
+            description: ev.message
+                .filter(|msg| !msg.trim().is_empty())
+                .map(|msg| truncate_excerpt(&escape_markdown(&msg), Embed::DESCRIPTION_MAX_LENGTH)),
+        })
+    }
+}
+
+impl TryFrom<gitlab::ReleaseEvent> for Embed {
+    type Error = RenderError;
+
+    fn try_from(ev: gitlab::ReleaseEvent) -> Result<Self, Self::Error> {
+        let timestamp = match &ev.released_at {
+            Some(released_at) => parse_timestamp("released_at", released_at)?,
+            None => parse_timestamp("created_at", &ev.created_at)?,
+        };
+        let url = ev.web_url();
+
+        let name = if ev.name == ev.tag {
+            ev.name
+        } else {
+            format!("{} ({})", ev.name, ev.tag)
+        };
+
+        let assets = ev.assets.links.iter()
+            .map(|link| (&link.name, &link.url))
+            .chain(ev.assets.sources.iter().map(|source| (&source.format, &source.url)))
+            .map(|(name, url)| format!("[{}]({})", escape_markdown(name), url))
+            .collect::<Vec<_>>();
+        let assets = if assets.is_empty() {
+            String::new()
+        } else {
+            format!("\n\n**Assets**\n{}", assets.join(" • "))
+        };
+
+        let notes_length = Embed::DESCRIPTION_MAX_LENGTH.saturating_sub(assets.chars().count());
+        let notes = ev.description.as_deref()
+            .map(|notes| neutralize_mentions(&discord_markdown(notes)))
+            .map(|notes| truncate_excerpt(&notes, notes_length))
+            .unwrap_or_default();
+        let description = format!("{}{}", notes, assets).trim().to_string();
+
+        Ok(Self {
+            author: None,
+            title: build_project_title!(
+                &ev.project, "Release {} {}", name, if ev.action == "update" { "updated" } else { "published" }
+            ),
+            url,
+            color: if ev.action == "update" { Color::INFO } else { Color::GOOD },
+            footer: Footer::new(ev.project.path_with_namespace, ev.project.avatar_url),
+            fields: Vec::new(),
+            thumbnail: None,
+            image: None,
+            timestamp: Some(timestamp),
+            description: if description.is_empty() { None } else { Some(description) },
+        })
+    }
+}
+
+impl TryFrom<gitlab::NoteEvent> for Embed {
+    type Error = RenderError;
+
+    fn try_from(ev: gitlab::NoteEvent) -> Result<Self, Self::Error> {
+        let timestamp = parse_timestamp("object_attributes.created_at", &ev.attributes.created_at)?;
+        let noteable = ev.noteable();
+        let url = ev.web_url();
+        let note = ev.attributes;
+        let image = markdown_image(&note.note, &ev.project.web_url).map(Image::new);
+
+        Ok(Self {
+            author: Some(Author::new(ev.user.username, ev.user.avatar_url)),
+            title: build_project_title!(&ev.project, "Comment on {}", noteable),
+            url,
             color: Color::INFO,
             footer: Footer::new(ev.project.path_with_namespace, ev.project.avatar_url),
+            fields: Vec::new(),
+            thumbnail: None,
+            image,
+            timestamp: Some(timestamp),
+            description: Some(truncate_excerpt(&neutralize_mentions(&discord_markdown(&note.note)), Embed::DESCRIPTION_MAX_LENGTH)),
+        })
+    }
+}
+
+impl TryFrom<gitlab::JobEvent> for Embed {
+    type Error = RenderError;
+
+    fn try_from(ev: gitlab::JobEvent) -> Result<Self, Self::Error> {
+        let timestamp = match &ev.build_finished_at {
+            Some(finished_at) => parse_timestamp("build_finished_at", finished_at)?,
+            None => parse_timestamp("build_created_at", &ev.build_created_at)?,
+        };
+
+        let mut details = vec![format!("**Ref:** {}", escape_markdown(&ev.git_ref))];
+        if let Some(runner) = ev.runner.as_ref().and_then(|r| r.description.as_ref()) {
+            details.push(format!("**Runner:** {}", escape_markdown(runner)));
+        }
+        if let Some(duration) = ev.build_duration {
+            details.push(format!("**Duration:** {}", format_duration(duration)));
+        }
+        if let Some(reason) = &ev.build_failure_reason {
+            details.push(format!("**Reason:** {}", reason.replace('_', " ")));
+        }
+        if ev.build_allow_failure {
+            details.push("*Allowed to fail*".to_string());
+        }
+
+        Ok(Self {
+            author: Some(Author::new(ev.user.username.clone(), ev.user.avatar_url.clone())),
+            title: build_project_title!(
+                &ev.project, "Job {} {} in stage {} ({})", ev.build_name, ev.build_status, ev.build_stage, ev.build_id
+            ),
+            url: ev.web_url(),
+            color: match &*ev.build_status {
+                "success" => Color::GOOD,
+                "failed" if ev.build_allow_failure => Color::ALERT,
+                "failed" => Color::BAD,
+                _ => Color::INFO,
+            },
+            footer: Footer::new(ev.project.path_with_namespace, ev.project.avatar_url),
+            fields: Vec::new(),
+            thumbnail: None,
+            image: None,
+            timestamp: Some(timestamp),
+            description: Some(details.join("\n")),
+        })
+    }
+}
+
+impl TryFrom<gitlab::DeploymentEvent> for Embed {
+    type Error = RenderError;
+
+    fn try_from(ev: gitlab::DeploymentEvent) -> Result<Self, Self::Error> {
+        let timestamp = parse_timestamp("status_changed_at", &ev.status_changed_at)?;
+
+        let environment = match &ev.environment_external_url {
+            Some(url) if !url.is_empty() => format!("[{}]({})", escape_markdown(&ev.environment), url),
+            _ => escape_markdown(&ev.environment),
+        };
+        let description = [
+            format!("**Environment:** {}", environment),
+            format!("**Commit:** [`{}`]({}) {}", ev.short_sha, ev.commit_url, escape_markdown(&ev.commit_title)),
+            format!("**Job:** [#{}]({})", ev.deployable_id, ev.deployable_url),
+        ];
+
+        Ok(Self {
+            author: Some(Author::new(ev.user.username, ev.user.avatar_url)),
+            title: build_project_title!(
+                &ev.project, "Deployment to {} {}", ev.environment, ev.status
+            ),
+            url: ev.deployable_url,
+            color: match &*ev.status {
+                "success" => Color::GOOD,
+                "failed" => Color::BAD,
+                "canceled" => Color::ALERT,
+                _ => Color::INFO,
+            },
+            footer: Footer::new(ev.project.path_with_namespace, ev.project.avatar_url),
+            fields: Vec::new(),
+            thumbnail: None,
+            image: None,
+            timestamp: Some(timestamp),
+            description: Some(description.join("\n")),
+        })
+    }
+}
+
+impl TryFrom<gitlab::FeatureFlagEvent> for Embed {
+    type Error = RenderError;
+
+    fn try_from(ev: gitlab::FeatureFlagEvent) -> Result<Self, Self::Error> {
+        let flag = ev.attributes;
+
+        Ok(Self {
+            author: Some(Author::new(ev.user.username, ev.user.avatar_url)),
+            title: build_project_title!(
+                &ev.project, "Feature flag {} turned {}", flag.name, if flag.active { "on" } else { "off" }
+            ),
+            url: format!("{}/-/feature_flags", ev.project.web_url),
+            color: if flag.active { Color::GOOD } else { Color::ALERT },
+            footer: Footer::new(ev.project.path_with_namespace, ev.project.avatar_url),
+            fields: Vec::new(),
+            thumbnail: None,
+            image: None,
             timestamp: None,
-            description: Some(join_commit_lines(&ev.commits)),
+            description: flag.description
+                .filter(|desc| !desc.trim().is_empty())
+                .map(|desc| truncate_excerpt(&escape_markdown(&desc), Embed::DESCRIPTION_MAX_LENGTH)),
+        })
+    }
+}
+
+impl TryFrom<gitlab::WikiPageEvent> for Embed {
+    type Error = RenderError;
+
+    fn try_from(ev: gitlab::WikiPageEvent) -> Result<Self, Self::Error> {
+        let page = ev.attributes;
+        let deleted = page.action == "delete";
+
+        let mut description = page.message
+            .filter(|msg| !msg.trim().is_empty())
+            .map(|msg| truncate_excerpt(&escape_markdown(&msg), Embed::DESCRIPTION_MAX_LENGTH / 2))
+            .unwrap_or_default();
+        if let (false, Some(diff_url)) = (deleted, &page.diff_url) {
+            description = format!("{}\n[View changes]({})", description, diff_url);
         }
+        let description = description.trim().to_string();
+
+        Ok(Self {
+            author: Some(Author::new(ev.user.username, ev.user.avatar_url)),
+            title: build_project_title!(
+                &ev.project, "Wiki page {} {}", page.title, match &*page.action {
+                    "create" => "created",
+                    "delete" => "deleted",
+                    _ => "updated",
+                }
+            ),
+            url: match (deleted, page.url) {
+                (false, Some(url)) => url,
+                _ => ev.wiki.web_url,
+            },
+            color: if deleted { Color::ALERT } else { Color::INFO },
+            footer: Footer::new(ev.project.path_with_namespace, ev.project.avatar_url),
+            fields: Vec::new(),
+            thumbnail: None,
+            image: None,
+            timestamp: None,
+            description: if description.is_empty() { None } else { Some(description) },
+        })
     }
 }
 
-impl From<gitlab::IssueEvent> for Embed {
-    fn from(ev: gitlab::IssueEvent) -> Self {
+impl TryFrom<gitlab::IssueEvent> for Embed {
+    type Error = RenderError;
+
+    fn try_from(ev: gitlab::IssueEvent) -> Result<Self, Self::Error> {
+        let url = ev.web_url();
         let issue = ev.attributes;
-        let timestamp = issue.created_at.parse().unwrap();
+        let timestamp = attributes_timestamp(&issue)?;
+        let changes = describe_changes(&ev.changes);
 
-        Self {
-            author: Author::new(ev.user.username, ev.user.avatar_url),
+        Ok(Self {
+            author: Some(Author::new(ev.user.username, ev.user.avatar_url)),
             title: build_project_title!(
-                &ev.project, "Issue {}: #{} {}", issue.state, issue.issue_id, issue.title
+                &ev.project, "Issue {}: #{} {}", action_verb(&issue.action), issue.issue_id, issue.title
             ),
-            url: issue.url,
+            url,
             footer: Footer::new(ev.project.path_with_namespace, ev.project.avatar_url),
+            fields: Vec::new(),
+            thumbnail: None,
+            image: None,
             timestamp: Some(timestamp),
-            color: if issue.state == "closed" { Color::GOOD } else { Color::INFO },
-            description: None,
-        }
+            color: if issue.action == "close" { Color::GOOD } else { Color::INFO },
+            description: if changes.is_empty() { None } else { Some(changes.join("\n")) },
+        })
     }
 }
 
-impl From<gitlab::MergeRequestEvent> for Embed {
-    fn from(ev: gitlab::MergeRequestEvent) -> Self {
+impl TryFrom<gitlab::MergeRequestEvent> for Embed {
+    type Error = RenderError;
+
+    fn try_from(ev: gitlab::MergeRequestEvent) -> Result<Self, Self::Error> {
+        let action = ev.action().to_string();
+        let url = ev.web_url();
         let mr = ev.attributes;
-        let timestamp = mr.created_at.parse().unwrap();
+        let timestamp = attributes_timestamp(&mr)?;
+        let changes = describe_changes(&ev.changes);
 
-        Self {
-            author: Author::new(ev.user.username, ev.user.avatar_url),
+        Ok(Self {
+            author: Some(Author::new(ev.user.username, ev.user.avatar_url)),
             title: build_project_title!(
-                &ev.project, "Merge request {}: !{} {}", mr.state, mr.issue_id, mr.title
+                &ev.project, "Merge request {}: !{} {}", action_verb(&action), mr.issue_id, mr.title
             ),
-            url: mr.url,
+            url,
             footer: Footer::new( ev.project.path_with_namespace, ev.project.avatar_url),
+            fields: Vec::new(),
+            thumbnail: None,
+            image: None,
             timestamp: Some(timestamp),
-            color: match &*mr.state {
-                "closed" => Color::ALERT,
-                "merged" => Color::GOOD,
+            color: match &*action {
+                "close" | "unapproved" | "unapproval" => Color::ALERT,
+                "merge" | "approved" | "approval" => Color::GOOD,
                 _ => Color::INFO,
             },
-            description: None,
+            description: if changes.is_empty() { None } else { Some(changes.join("\n")) },
+        })
+    }
+}
+
+fn action_verb(action: &str) -> &str {
+    match action {
+        "open" => "opened",
+        "close" => "closed",
+        "reopen" => "reopened",
+        "update" => "updated",
+        "merge" => "merged",
+        "approved" | "approval" => "approved",
+        "unapproved" | "unapproval" => "unapproved",
+        "ready" => "ready for review",
+        other => other,
+    }
+}
+
+fn attributes_timestamp(attributes: &gitlab::IssueAttributes) -> Result<DateTime<chrono::FixedOffset>, RenderError> {
+    match &attributes.updated_at {
+        Some(updated_at) => parse_timestamp("object_attributes.updated_at", updated_at),
+        None => parse_timestamp("object_attributes.created_at", &attributes.created_at),
+    }
+}
+
+/// Renders the interesting parts of an update, e.g. `labels: +bug −triage`.
+pub fn describe_changes(changes: &gitlab::Changes) -> Vec<String> {
+    use gitlab::Change;
+
+    let mut lines = Vec::new();
+
+    if let Some(Change { previous: Some(previous), current: Some(current) }) = &changes.title {
+        lines.push(format!("**title:** {} → {}", escape_markdown(previous), escape_markdown(current)));
+    }
+
+    if changes.description.is_some() {
+        lines.push("**description** changed".to_string());
+    }
+
+    if let Some(labels) = &changes.labels {
+        let titles = |labels: &Option<Vec<gitlab::Label>>| {
+            labels.iter().flatten().map(|l| escape_markdown(&l.title)).collect::<Vec<_>>()
+        };
+        let (previous, current) = (titles(&labels.previous), titles(&labels.current));
+        let added = current.iter().filter(|l| !previous.contains(l)).map(|l| format!("+{}", l));
+        let removed = previous.iter().filter(|l| !current.contains(l)).map(|l| format!("−{}", l));
+        let diff = added.chain(removed).collect::<Vec<_>>();
+
+        if !diff.is_empty() {
+            lines.push(format!("**labels:** {}", diff.join(" ")));
         }
     }
+
+    for (name, users) in &[("assignee", &changes.assignees), ("reviewer", &changes.reviewers)] {
+        if let Some(users) = users {
+            let names = |users: &Option<Vec<gitlab::UserRef>>| {
+                let names = users.iter().flatten().map(|u| escape_markdown(&u.username)).collect::<Vec<_>>();
+                if names.is_empty() { "nobody".to_string() } else { names.join(", ") }
+            };
+            lines.push(format!("**{}:** {} → {}", name, names(&users.previous), names(&users.current)));
+        }
+    }
+
+    if let Some(Change { previous: Some(previous), current: Some(current) }) = &changes.draft {
+        let yes_no = |draft: bool| if draft { "yes" } else { "no" };
+        lines.push(format!("**draft:** {} → {}", yes_no(*previous), yes_no(*current)));
+    }
+
+    lines
 }
 
-impl From<gitlab::PipelineEvent> for Embed {
-    fn from(ev: gitlab::PipelineEvent) -> Self {
-        let pipeline = ev.attributes;
-        let timestamp = pipeline.created_at.parse().unwrap();
+impl TryFrom<gitlab::PipelineEvent> for Embed {
+    type Error = RenderError;
 
-        Self {
-            author: Author::new(ev.user.username, ev.user.avatar_url),
+    fn try_from(ev: gitlab::PipelineEvent) -> Result<Self, Self::Error> {
+        let timestamp = parse_timestamp("object_attributes.created_at", &ev.attributes.created_at)?;
+        let url = ev.web_url();
+        let project_url = ev.project.web_url.clone();
+
+        let fields = ev.stages().into_iter()
+            .take(Embed::MAX_FIELDS)
+            .map(|(stage, builds)| {
+                let lines = builds.iter()
+                    .map(|build| {
+                        let mut line = format!(
+                            "{} [{}]({}/-/jobs/{})",
+                            build_status_icon(build), escape_markdown(&build.name), project_url, build.id
+                        );
+                        if let Some(duration) = build.duration {
+                            line.push_str(&format!(" {}", format_duration(duration)));
+                        }
+                        line
+                    })
+                    .collect::<Vec<_>>();
+                Field::from_lines(stage, &lines, true)
+            })
+            .collect();
+
+        let pipeline = &ev.attributes;
+        let mut description = vec![
+            format!(
+                "**Commit:** [`{:.8}`]({}) {}",
+                ev.commit.id, ev.commit.web_url(&ev.project), escape_markdown(ev.commit.message.lines().next().unwrap_or_default())
+            ),
+        ];
+        if let Some(duration) = pipeline.duration {
+            description.push(format!("**Duration:** {}", format_duration(duration as f64)));
+        }
+        if let Some(source) = &pipeline.source {
+            description.push(format!("**Source:** {}", pipeline_source(source)));
+        }
+        if let Some(mr) = &ev.merge_request {
+            description.push(format!("**Merge request:** [!{} {}]({})", mr.iid, escape_markdown(&mr.title), ev.merge_request_url(mr)));
+        }
+
+        Ok(Self {
+            author: Some(Author::new(ev.user.username, ev.user.avatar_url)),
             title: build_project_title!(
                 &ev.project, "Pipeline for {} {} ({})", pipeline.git_ref, pipeline.detailed_status, pipeline.id
             ),
-            url: ev.commit.url,
+            url,
             footer: Footer::new(ev.project.path_with_namespace, ev.project.avatar_url),
+            fields,
+            thumbnail: None,
+            image: None,
             timestamp: Some(timestamp),
             color: match &*pipeline.status {
                 "success" => Color::GOOD,
                 "failed" => Color::BAD,
                 _ => Color::INFO,
             },
-            description: None,
+            description: Some(description.join("\n")),
+        })
+    }
+}
+
+fn build_status_icon(build: &gitlab::Build) -> &'static str {
+    match &*build.status {
+        "success" => "✅",
+        "failed" if build.allow_failure => "⚠️",
+        "failed" => "❌",
+        "running" => "🔄",
+        "pending" | "created" | "waiting_for_resource" | "preparing" | "scheduled" => "⏳",
+        "canceled" => "⛔",
+        "skipped" => "⏭️",
+        "manual" => "▶️",
+        _ => "❔",
+    }
+}
+
+fn pipeline_source(source: &str) -> String {
+    match source {
+        "merge_request_event" => "merge request".to_string(),
+        "api" => "API".to_string(),
+        other => other.replace('_', " "),
+    }
+}
+
+/// A single line summary of a failed job, used when combining jobs into the pipeline message.
+pub fn job_summary_line(ev: &gitlab::JobEvent) -> String {
+    let mut line = format!("[{}]({}) in stage `{}`", escape_markdown(&ev.build_name), ev.web_url(), ev.build_stage);
+
+    if let Some(runner) = ev.runner.as_ref().and_then(|r| r.description.as_ref()) {
+        line.push_str(&format!(" on {}", escape_markdown(runner)));
+    }
+    if let Some(duration) = ev.build_duration {
+        line.push_str(&format!(" after {}", format_duration(duration)));
+    }
+    if let Some(reason) = &ev.build_failure_reason {
+        line.push_str(&format!(": {}", reason.replace('_', " ")));
+    }
+
+    line
+}
+
+impl Embed {
+    /// Appends a section of lines to the description, dropping lines that don't fit.
+    pub fn append_section(&mut self, heading: &str, lines: &[String]) {
+        let mut description = self.description.take().unwrap_or_default();
+        if !description.is_empty() {
+            description.push_str("\n\n");
+        }
+        description.push_str(&format!("**{}**", heading));
+
+        for line in lines {
+            if description.chars().count() + line.chars().count() + 1 > Self::DESCRIPTION_MAX_LENGTH {
+                break;
+            }
+            description.push('\n');
+            description.push_str(line);
         }
+
+        self.description = Some(description);
     }
 }
 
-fn join_commit_lines(commits: &[gitlab::Commit]) -> String {
-    let mut chars = 0;
+fn format_duration(secs: f64) -> String {
+    let secs = secs.max(0.0).round() as u64;
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", seconds),
+        (0, _) => format!("{}m {}s", minutes, seconds),
+        _ => format!("{}h {}m {}s", hours, minutes, seconds),
+    }
+}
 
-    commits.iter()
-        .filter_map(|commit| {
-            let line = commit_line(commit);
-            if chars + line.len() + 1 > Embed::DESCRIPTION_MAX_LENGTH {
-                None
+/// Parses the timestamp formats used by GitLab, which differ between event types.
+fn parse_timestamp(field: &'static str, value: &str) -> Result<DateTime<chrono::FixedOffset>, RenderError> {
+    use chrono::{NaiveDateTime, TimeZone, Utc};
+
+    DateTime::parse_from_rfc3339(value)
+        .or_else(|_| DateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S %z"))
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S UTC")
+                .map(|naive| Utc.from_utc_datetime(&naive).into())
+        })
+        .map_err(|_| RenderError::InvalidTimestamp { field, value: value.to_string() })
+}
+
+/// Adapts GitLab flavored markdown to what Discord embeds are able to display.
+fn discord_markdown(text: &str) -> String {
+    let mut in_code_block = false;
+    let mut previous_blank = false;
+    let mut lines = Vec::new();
+
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+
+        if trimmed.starts_with("```") {
+            in_code_block = !in_code_block;
+        } else if !in_code_block {
+            if trimmed.is_empty() {
+                if previous_blank {
+                    continue;
+                }
+                previous_blank = true;
+                lines.push(String::new());
+                continue;
+            }
+
+            let heading = trimmed.trim_start_matches('#');
+            if heading.len() < trimmed.len() && heading.starts_with(' ') {
+                lines.push(format!("**{}**", heading.trim()));
             } else {
-                chars += line.len() + 1;
-                Some(line)
+                // Discord does not embed images, link to them instead
+                lines.push(line.replace("![", "["));
             }
+            previous_blank = false;
+            continue;
+        }
+
+        previous_blank = false;
+        lines.push(line.to_string());
+    }
+
+    lines.join("\n").trim().to_string()
+}
+
+/// Finds the first image of a GitLab markdown text, resolving uploads against the project.
+fn markdown_image(text: &str, project_url: &str) -> Option<String> {
+    text.match_indices("![").find_map(|(pos, _)| {
+        let rest = &text[pos + 2..];
+        let target = &rest[rest.find("](")? + 2..];
+        let url = target[..target.find(')')?].split_whitespace().next()?;
+
+        if url.starts_with("https://") || url.starts_with("http://") {
+            Some(url.to_string())
+        } else if url.starts_with('/') {
+            Some(format!("{}{}", project_url.trim_end_matches('/'), url))
+        } else {
+            None
+        }
+    })
+}
+
+/// Shortens text to at most `max_length` characters, preferring to cut at
+/// whitespace and closing code blocks that would otherwise stay open.
+fn truncate_excerpt(text: &str, max_length: usize) -> String {
+    const CODE_FENCE: &str = "\n```";
+
+    if text.chars().count() <= max_length {
+        return text.to_string();
+    }
+
+    let keep = max_length.saturating_sub(ELLIPSIS.chars().count() + CODE_FENCE.chars().count());
+    let mut excerpt = take_graphemes(text, keep);
+
+    if let Some(pos) = excerpt.rfind(char::is_whitespace) {
+        if pos > keep / 2 {
+            excerpt.truncate(pos);
+        }
+    }
+
+    excerpt = excerpt.trim_end().to_string();
+    excerpt.push_str(ELLIPSIS);
+
+    if excerpt.matches("```").count() % 2 == 1 {
+        excerpt.push_str(CODE_FENCE);
+    }
+
+    excerpt
+}
+
+const ELLIPSIS: &str = "…";
+
+/// Shortens text to at most `max_length` characters, never splitting a
+/// grapheme like an emoji with modifiers, and marks the cut with an ellipsis.
+pub fn truncate(text: &str, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text.to_string();
+    }
+
+    let mut truncated = take_graphemes(text, max_length.saturating_sub(ELLIPSIS.chars().count()));
+    truncated.push_str(ELLIPSIS);
+    truncated
+}
+
+/// Takes whole graphemes as long as they fit into `max_length` characters.
+fn take_graphemes(text: &str, max_length: usize) -> String {
+    let mut length = 0;
+
+    text.graphemes(true)
+        .take_while(|grapheme| {
+            length += grapheme.chars().count();
+            length <= max_length
         })
-        .collect::<Vec<_>>()
-        .join("\n")
+        .collect()
+}
+
+/// Escapes markdown in user controlled text, so it is displayed as written.
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for line in text.split('\n') {
+        let mut at_line_start = true;
+
+        for c in line.chars() {
+            let block_marker = at_line_start && matches!(c, '#' | '>' | '-');
+            if block_marker || matches!(c, '\\' | '*' | '_' | '~' | '`' | '|' | '[' | ']' | '<') {
+                escaped.push('\\');
+            }
+            if !c.is_whitespace() {
+                at_line_start = false;
+            }
+            escaped.push(c);
+        }
+        escaped.push('\n');
+    }
+    escaped.pop();
+
+    neutralize_mentions(&escaped)
 }
 
-fn commit_line(commit: &gitlab::Commit) -> String {
+/// Breaks `@everyone` and `@here`, which would otherwise read like a ping.
+pub fn neutralize_mentions(text: &str) -> String {
+    text.replace("@everyone", "@\u{200B}everyone")
+        .replace("@here", "@\u{200B}here")
+}
+
+/// Lists as many commits as fit, linking to the rest with `…and N more commits`.
+fn join_commit_lines(commits: &[gitlab::Commit], project: &gitlab::Project, total: u64, more_url: &str) -> String {
+    let more_line = |count: u64| format!("[…and {}]({})", plural(count, "more commit"), more_url);
+    let reserved = more_line(total).chars().count() + 1;
+    let mut lines = Vec::new();
+    let mut chars = 0;
+
+    for commit in commits {
+        let line = commit_line(commit, project);
+        let length = line.chars().count() + 1;
+        if chars + length + reserved > Embed::DESCRIPTION_MAX_LENGTH {
+            break;
+        }
+        chars += length;
+        lines.push(line);
+    }
+
+    let remaining = total.saturating_sub(lines.len() as u64);
+    if remaining > 0 {
+        lines.push(more_line(remaining));
+    }
+
+    lines.join("\n")
+}
+
+fn commit_line(commit: &gitlab::Commit, project: &gitlab::Project) -> String {
     format!(
         "[`{id:.8}`]({url:}) {msg:} - **{author:}**",
         id = commit.id,
-        url = commit.url,
-        msg = commit.message.lines().next().unwrap(),
-        author = commit.author.name,
+        url = commit.web_url(project),
+        msg = escape_markdown(commit.message.lines().next().unwrap_or_default()),
+        author = escape_markdown(&commit.author.name),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json as json;
+
+    fn render<E>(data: &str) -> Embed
+    where E: serde::de::DeserializeOwned,
+          Embed: TryFrom<E, Error = RenderError>,
+    {
+        let event = json::from_str::<E>(data).unwrap();
+        Embed::try_from(event).unwrap()
+    }
+
+    fn push_event(change: impl FnOnce(&mut json::Value)) -> Embed {
+        let mut event = json::from_str::<json::Value>(include_str!("../../tests/data/push_event.json")).unwrap();
+        change(&mut event);
+        Embed::try_from(json::from_value::<gitlab::PushEvent>(event).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn render_push_event() {
+        let embed = render::<gitlab::PushEvent>(include_str!("../../tests/data/push_event.json"));
+        assert_eq!(embed.title.0, "Project - 5 new commits in master");
+        assert!(embed.url.ends_with("/-/compare/95790bf891e76fee5e1747ab589903a6a1f80f22...da1560886d4f094c3e6c9ef40349f7d38b5d27d7"));
+        assert!(!embed.description.unwrap().contains("more commits"));
+    }
+
+    #[test]
+    fn render_push_with_more_commits() {
+        let embed = push_event(|ev| {
+            ev["ref"] = "refs/heads/feature/foo".into();
+            ev["total_commits_count"] = 25.into();
+        });
+        assert_eq!(embed.title.0, "Project - 25 new commits in feature/foo");
+        assert!(embed.description.unwrap().ends_with(&format!("[…and 20 more commits]({})", embed.url)));
+    }
+
+    #[test]
+    fn render_branch_pushes() {
+        let created = push_event(|ev| ev["before"] = "0".repeat(40).into());
+        assert_eq!(created.title.0, "Project - Branch master created with 5 new commits");
+
+        let deleted = push_event(|ev| {
+            ev["after"] = "0".repeat(40).into();
+            ev["commits"] = json::json!([]);
+            ev["total_commits_count"] = 0.into();
+        });
+        assert_eq!(deleted.title.0, "Project - Branch master deleted");
+        assert!(deleted.description.is_none());
+
+        let forced = push_event(|ev| {
+            ev["commits"] = json::json!([]);
+            ev["total_commits_count"] = 0.into();
+        });
+        assert_eq!(forced.title.0, "Project - Force pushed to master");
+    }
+
+    #[test]
+    fn render_issue_event() {
+        let embed = render::<gitlab::IssueEvent>(include_str!("../../tests/data/issue_opened_event.json"));
+        assert!(embed.timestamp.is_some());
+    }
+
+    #[test]
+    fn render_mr_event() {
+        render::<gitlab::MergeRequestEvent>(include_str!("../../tests/data/mr_merged_event.json"));
+    }
+
+    #[test]
+    fn render_mr_test_event() {
+        let embed = render::<gitlab::MergeRequestEvent>(include_str!("../../tests/data/mr_test_event.json"));
+        assert_eq!(embed.url, "https://gitlab.com/testmaster/project/-/merge_requests/4");
+
+        // Projects without an avatar get no footer icon rather than an empty URL
+        let footer = json::to_value(&embed.footer).unwrap();
+        assert!(footer.get("icon_url").is_none());
+    }
+
+    #[test]
+    fn render_release_event() {
+        let embed = render::<gitlab::ReleaseEvent>(include_str!("../../tests/data/release_created_event.json"));
+        let description = embed.description.unwrap();
+        assert!(description.starts_with("**Highlights**"));
+        assert!(description.ends_with("[Changelog](https://gitlab.com/testmaster/project/-/blob/master/CHANGELOG.md) • [zip](https://gitlab.com/testmaster/project/-/archive/v1.0.0/project-v1.0.0.zip) • [tar.gz](https://gitlab.com/testmaster/project/-/archive/v1.0.0/project-v1.0.0.tar.gz)"));
+    }
+
+    #[test]
+    fn render_deployment_event() {
+        let embed = render::<gitlab::DeploymentEvent>(include_str!("../../tests/data/deployment_event.json"));
+        assert_eq!(embed.title.0, "Project - Deployment to staging success");
+        assert_eq!(embed.url, "https://gitlab.com/testmaster/project/-/jobs/796");
+        assert_eq!(embed.color, Color::GOOD);
+        assert!(embed.description.unwrap().contains("**Environment:** [staging](https://staging.example.com)"));
+    }
+
+    #[test]
+    fn render_feature_flag_event() {
+        let embed = render::<gitlab::FeatureFlagEvent>(include_str!("../../tests/data/feature_flag_event.json"));
+        assert_eq!(embed.title.0, "Project - Feature flag auto-ban turned on");
+        assert_eq!(embed.url, "https://gitlab.com/testmaster/project/-/feature_flags");
+        assert_eq!(embed.color, Color::GOOD);
+        assert_eq!(embed.description.as_deref(), Some("Automatically ban detected cheaters"));
+    }
+
+    #[test]
+    fn render_wiki_page_event() {
+        let embed = render::<gitlab::WikiPageEvent>(include_str!("../../tests/data/wiki_page_event.json"));
+        assert_eq!(embed.title.0, "Project - Wiki page Anti-cheat updated");
+        assert_eq!(embed.url, "https://gitlab.com/testmaster/project/-/wikis/anti-cheat");
+        assert_eq!(embed.color, Color::INFO);
+        assert!(embed.description.unwrap().ends_with("[View changes](https://gitlab.com/testmaster/project/-/wikis/anti-cheat/diff?version_id=78ee4a6705abfbff)"));
+    }
+
+    #[test]
+    fn truncate_excerpt_on_char_boundary() {
+        let text = "ä".repeat(100);
+        let excerpt = truncate_excerpt(&text, 10);
+        assert_eq!(excerpt.chars().count(), 6);
+        assert!(excerpt.ends_with('…'));
+
+        let excerpt = truncate_excerpt("```\nlet x = 1;\nlet y = 2;\n```", 20);
+        assert!(excerpt.ends_with("```"));
+        assert!(excerpt.chars().count() <= 20);
+    }
+
+    #[test]
+    fn render_job_event() {
+        let embed = render::<gitlab::JobEvent>(include_str!("../../tests/data/job_failed_event.json"));
+        assert_eq!(embed.url, "https://gitlab.com/testmaster/project/-/jobs/1977");
+        assert!(embed.description.unwrap().contains("**Duration:** 1m 35s"));
+    }
+
+    #[test]
+    fn render_mr_updated_event() {
+        let embed = render::<gitlab::MergeRequestEvent>(include_str!("../../tests/data/mr_updated_event.json"));
+        assert_eq!(embed.title.0, "Project - Merge request ready for review: !4 Implement anti-cheat system");
+        let description = embed.description.unwrap();
+        assert!(description.contains("**labels:** +bug −triage"));
+        assert!(description.contains("**assignee:** alice → bob"));
+    }
+
+    #[test]
+    fn render_pipeline_event() {
+        let embed = render::<gitlab::PipelineEvent>(include_str!("../../tests/data/pipeline_failed_event.json"));
+        assert_eq!(embed.url, "https://gitlab.com/testmaster/project/-/pipelines/31");
+        assert_eq!(embed.fields.len(), 3);
+        assert_eq!(embed.fields[1].value, concat!(
+            "❌ [rspec](https://gitlab.com/testmaster/project/-/jobs/1977) 1m 35s\n",
+            "⚠️ [rubocop](https://gitlab.com/testmaster/project/-/jobs/1978) 12s",
+        ));
+        let description = embed.description.unwrap();
+        assert!(description.contains("**Duration:** 3m 2s"));
+        assert!(description.contains("**Source:** merge request"));
+        assert!(description.contains("[!4 Implement anti-cheat system]"));
+    }
+
+    #[test]
+    fn split_oversized_embed() {
+        let mut embed = render::<gitlab::PipelineEvent>(include_str!("../../tests/data/pipeline_failed_event.json"));
+        let lines = vec!["x".repeat(100); 20];
+        embed.fields = (0..30).map(|i| Field::from_lines(&format!("stage {}", i), &lines, true)).collect();
+
+        let messages = WebhookMessage::split(vec![embed], Some("GitLab".into()), None);
+        let embeds = messages.iter().flat_map(|message| &message.embeds).collect::<Vec<_>>();
+
+        assert_eq!(embeds.iter().map(|embed| embed.fields.len()).sum::<usize>(), 30);
+        assert!(embeds.len() > 1);
+        for message in &messages {
+            assert!(message.embeds.len() <= WebhookMessage::MAX_EMBEDS);
+            assert!(message.embeds.iter().map(Embed::text_length).sum::<usize>() <= WebhookMessage::MAX_LENGTH);
+        }
+    }
+
+    #[test]
+    fn mention_only_mapped_targets() {
+        let embed = render::<gitlab::PipelineEvent>(include_str!("../../tests/data/pipeline_failed_event.json"));
+        let mut message = WebhookMessage::split(vec![embed], None, None).remove(0);
+        message.mention(&[DiscordTarget::User("80351110224678912".into()), DiscordTarget::Role("41771983423143936".into())]);
+
+        let json = json::to_value(&message).unwrap();
+        assert_eq!(json["content"], "<@80351110224678912> <@&41771983423143936>");
+        assert_eq!(json["allowed_mentions"], json::json!({
+            "parse": [],
+            "users": ["80351110224678912"],
+            "roles": ["41771983423143936"],
+        }));
+    }
+
+    #[test]
+    fn find_markdown_image() {
+        let project = "https://gitlab.com/group/project";
+        assert_eq!(
+            markdown_image("see ![screenshot](/uploads/abc/shot.png)", project).as_deref(),
+            Some("https://gitlab.com/group/project/uploads/abc/shot.png"),
+        );
+        assert_eq!(markdown_image("no images [here](/uploads/x)", project), None);
+    }
+
+    #[test]
+    fn truncate_keeps_graphemes() {
+        assert_eq!(truncate("Grüße", 10), "Grüße");
+        assert_eq!(truncate("Grüße aus Köln", 6), "Grüße…");
+        // A family emoji is a single grapheme made of several characters
+        assert_eq!(truncate("ab👨‍👩‍👧", 6), "ab…");
+        assert_eq!(Title::new("ä".repeat(300)).0.chars().count(), Title::MAX_LENGTH);
+    }
+
+    #[test]
+    fn escape_user_text() {
+        assert_eq!(escape_markdown("fix *all* the `bugs` @everyone"), "fix \\*all\\* the \\`bugs\\` @\u{200B}everyone");
+        assert_eq!(escape_markdown("# not a heading\n> nor a quote"), "\\# not a heading\n\\> nor a quote");
+        assert_eq!(escape_markdown("a-b #4"), "a-b #4");
+    }
+
+    #[test]
+    fn parse_timestamp_formats() {
+        let expected = parse_timestamp("test", "2018-06-19T12:28:46Z").unwrap();
+        assert_eq!(parse_timestamp("test", "2018-06-19 12:28:46 UTC").unwrap(), expected);
+        assert_eq!(parse_timestamp("test", "2018-06-19 14:28:46 +0200").unwrap(), expected);
+        assert!(parse_timestamp("test", "yesterday").is_err());
+    }
+}
@@ -1,37 +1,119 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+
+/// Shown for users without an avatar, the same placeholder GitLab falls back to
+pub const DEFAULT_AVATAR_URL: &str = "https://www.gravatar.com/avatar/?s=80&d=identicon";
 
 #[derive(Debug, Deserialize)]
 pub struct PipelineAttributes {
     pub id: u64,
-    pub action: String,
     pub status: String,
+    #[serde(default)]
     pub detailed_status: String,
     pub created_at: String,
     #[serde(rename = "ref")]
     pub git_ref: String,
+    #[serde(default)]
+    pub source: Option<String>,
+    #[serde(default)]
+    pub stages: Vec<String>,
+    #[serde(default)]
+    pub duration: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Build {
+    pub id: u64,
+    pub stage: String,
+    pub name: String,
+    pub status: String,
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub allow_failure: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PipelineMergeRequest {
+    pub iid: u64,
+    pub title: String,
+    #[serde(default)]
+    pub url: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct IssueAttributes {
     #[serde(rename = "iid")]
     pub issue_id: u64,
+    /// Filled in from the state when missing, see [`issue_attributes`]
+    #[serde(default)]
     pub action: String,
     pub state: String,
     pub title: String,
-    pub url: String,
+    #[serde(default)]
+    pub url: Option<String>,
     pub created_at: String,
+    #[serde(default)]
+    pub updated_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Change<T> {
+    pub previous: Option<T>,
+    pub current: Option<T>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Label {
+    pub title: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserRef {
+    pub username: String,
+}
+
+/// The attributes changed by an update, as far as we render them.
+#[derive(Debug, Default, Deserialize)]
+pub struct Changes {
+    pub title: Option<Change<String>>,
+    pub description: Option<Change<String>>,
+    pub labels: Option<Change<Vec<Label>>>,
+    pub assignees: Option<Change<Vec<UserRef>>>,
+    pub reviewers: Option<Change<Vec<UserRef>>>,
+    #[serde(alias = "work_in_progress")]
+    pub draft: Option<Change<bool>>,
+}
+
+impl Changes {
+    /// Whether a merge request was marked as ready by this update.
+    pub fn marked_ready(&self) -> bool {
+        let draft_prefix = |title: &str| {
+            let title = title.trim_start().to_lowercase();
+            title.starts_with("draft:") || title.starts_with("[draft]") || title.starts_with("wip:")
+        };
+
+        match (&self.draft, &self.title) {
+            (Some(Change { previous: Some(true), current: Some(false) }), _) => true,
+            (None, Some(Change { previous: Some(previous), current: Some(current) })) => {
+                draft_prefix(previous) && !draft_prefix(current)
+            },
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct User {
     pub username: String,
+    #[serde(default = "default_avatar", deserialize_with = "avatar")]
     pub avatar_url: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Commit {
     pub id: String,
-    pub url: String,
+    #[serde(default)]
+    pub url: Option<String>,
     pub message: String,
     pub author: CommitAuthor,
 }
@@ -39,6 +121,8 @@ pub struct Commit {
 #[derive(Debug, Deserialize)]
 pub struct CommitAuthor {
     pub name: String,
+    #[serde(default)]
+    pub email: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -46,36 +130,365 @@ pub struct Project {
     pub name: String,
     pub web_url: String,
     pub path_with_namespace: String,
+    /// Empty for projects without an avatar
+    #[serde(default, deserialize_with = "nullable")]
     pub avatar_url: String,
 }
 
+impl Commit {
+    pub fn web_url(&self, project: &Project) -> String {
+        self.url.clone().unwrap_or_else(|| format!("{}/-/commit/{}", project.web_url, self.id))
+    }
+}
+
 
 #[derive(Debug, Deserialize)]
 pub struct PushEvent {
     #[serde(rename = "ref")]
     pub git_ref: String,
+    pub before: String,
+    pub after: String,
     #[serde(rename = "user_username")]
     pub username: String,
+    #[serde(default = "default_avatar", deserialize_with = "avatar")]
     pub user_avatar: String,
     pub project: Project,
+    #[serde(default)]
     pub commits: Vec<Commit>,
+    #[serde(default)]
     pub total_commits_count: u64,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct IssueEvent {
+pub struct TagPushEvent {
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    pub after: String,
+    pub message: Option<String>,
+    #[serde(rename = "user_username")]
+    pub username: String,
+    #[serde(default = "default_avatar", deserialize_with = "avatar")]
+    pub user_avatar: String,
+    pub project: Project,
+}
+
+impl PushEvent {
+    /// The branch name, keeping slashes of names like `feature/foo`.
+    pub fn branch_name(&self) -> &str {
+        self.git_ref.strip_prefix("refs/heads/").unwrap_or(&self.git_ref)
+    }
+
+    /// New branches are pushes from the null commit.
+    pub fn is_creation(&self) -> bool {
+        is_null_sha(&self.before)
+    }
+
+    /// Branch deletions are pushes to the null commit.
+    pub fn is_deletion(&self) -> bool {
+        is_null_sha(&self.after)
+    }
+
+    /// GitLab does not flag force pushes, but a push moving an existing branch
+    /// without adding any commits can only have rewritten its history.
+    pub fn is_force_push(&self) -> bool {
+        !self.is_creation() && !self.is_deletion() && self.total_commits_count == 0
+    }
+}
+
+impl TagPushEvent {
+    pub fn tag_name(&self) -> &str {
+        self.git_ref.strip_prefix("refs/tags/").unwrap_or(&self.git_ref)
+    }
+
+    /// Tag deletions are pushes to the null commit.
+    pub fn is_deletion(&self) -> bool {
+        is_null_sha(&self.after)
+    }
+}
+
+fn is_null_sha(sha: &str) -> bool {
+    sha.chars().all(|c| c == '0')
+}
+
+fn default_avatar() -> String {
+    DEFAULT_AVATAR_URL.to_string()
+}
+
+/// Users without an avatar have it set to `null`.
+fn avatar<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let avatar = Option::<String>::deserialize(deserializer)?;
+    Ok(avatar.filter(|url| !url.is_empty()).unwrap_or_else(default_avatar))
+}
+
+fn nullable<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Test events sent from the GitLab UI leave out the action, which mostly follows from the state.
+fn issue_attributes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IssueAttributes, D::Error> {
+    let mut attributes = IssueAttributes::deserialize(deserializer)?;
+    if attributes.action.is_empty() {
+        attributes.action = match &*attributes.state {
+            "opened" => "open",
+            "closed" => "close",
+            "merged" => "merge",
+            _ => "update",
+        }.to_string();
+    }
+
+    Ok(attributes)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReleaseLink {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReleaseSource {
+    pub format: String,
+    pub url: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ReleaseAssets {
+    #[serde(default)]
+    pub links: Vec<ReleaseLink>,
+    #[serde(default)]
+    pub sources: Vec<ReleaseSource>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReleaseEvent {
+    pub action: String,
+    pub name: String,
+    pub tag: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    pub created_at: String,
+    #[serde(default)]
+    pub released_at: Option<String>,
+    pub project: Project,
+    #[serde(default)]
+    pub assets: ReleaseAssets,
+}
+
+impl ReleaseEvent {
+    pub fn web_url(&self) -> String {
+        self.url.clone().unwrap_or_else(|| format!("{}/-/releases/{}", self.project.web_url, self.tag))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NoteAttributes {
+    pub note: String,
+    pub noteable_type: String,
+    #[serde(default)]
+    pub system: bool,
+    #[serde(default)]
+    pub url: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NoteTarget {
+    pub iid: u64,
+    pub title: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Snippet {
+    pub id: u64,
+    pub title: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NoteEvent {
+    pub user: User,
+    pub project: Project,
+    #[serde(rename = "object_attributes")]
+    pub attributes: NoteAttributes,
+    pub merge_request: Option<NoteTarget>,
+    pub issue: Option<NoteTarget>,
+    pub commit: Option<Commit>,
+    pub snippet: Option<Snippet>,
+}
+
+impl NoteEvent {
+    /// A short reference to the commented object, like `merge request !4`.
+    pub fn noteable(&self) -> String {
+        let title = match &*self.attributes.noteable_type {
+            "MergeRequest" => self.merge_request.as_ref().map(|mr| format!("merge request !{} {}", mr.iid, mr.title)),
+            "Issue" => self.issue.as_ref().map(|issue| format!("issue #{} {}", issue.iid, issue.title)),
+            "Commit" => self.commit.as_ref().map(|commit| format!("commit {:.8}", commit.id)),
+            "Snippet" => self.snippet.as_ref().map(|snippet| format!("snippet ${} {}", snippet.id, snippet.title)),
+            _ => None,
+        };
+
+        title.unwrap_or_else(|| self.attributes.noteable_type.to_lowercase())
+    }
+
+    /// Links to the comment, or to the commented object if the URL of the comment is missing.
+    pub fn web_url(&self) -> String {
+        if let Some(url) = &self.attributes.url {
+            return url.clone();
+        }
+
+        let web_url = &self.project.web_url;
+        let path = match &*self.attributes.noteable_type {
+            "MergeRequest" => self.merge_request.as_ref().map(|mr| format!("merge_requests/{}", mr.iid)),
+            "Issue" => self.issue.as_ref().map(|issue| format!("issues/{}", issue.iid)),
+            "Commit" => self.commit.as_ref().map(|commit| format!("commit/{}", commit.id)),
+            "Snippet" => self.snippet.as_ref().map(|snippet| format!("snippets/{}", snippet.id)),
+            _ => None,
+        };
+
+        match path {
+            Some(path) => format!("{}/-/{}", web_url, path),
+            None => web_url.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Runner {
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JobEvent {
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    pub build_id: u64,
+    pub build_name: String,
+    pub build_stage: String,
+    pub build_status: String,
+    pub build_created_at: String,
+    pub build_finished_at: Option<String>,
+    pub build_duration: Option<f64>,
+    #[serde(default)]
+    pub build_allow_failure: bool,
+    pub build_failure_reason: Option<String>,
+    pub pipeline_id: u64,
+    pub runner: Option<Runner>,
+    pub user: User,
+    pub project: Project,
+}
+
+impl JobEvent {
+    pub fn web_url(&self) -> String {
+        format!("{}/-/jobs/{}", self.project.web_url, self.build_id)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeploymentEvent {
+    pub status: String,
+    pub status_changed_at: String,
+    pub deployable_id: u64,
+    pub deployable_url: String,
+    pub environment: String,
+    pub environment_external_url: Option<String>,
+    pub short_sha: String,
+    pub commit_url: String,
+    pub commit_title: String,
+    pub user: User,
+    pub project: Project,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeatureFlagAttributes {
+    pub name: String,
+    pub description: Option<String>,
+    pub active: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeatureFlagEvent {
+    pub user: User,
+    pub project: Project,
+    #[serde(rename = "object_attributes")]
+    pub attributes: FeatureFlagAttributes,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Wiki {
+    pub web_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WikiPageAttributes {
+    pub title: String,
+    pub message: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    pub action: String,
+    pub diff_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WikiPageEvent {
     pub user: User,
     pub project: Project,
+    pub wiki: Wiki,
     #[serde(rename = "object_attributes")]
+    pub attributes: WikiPageAttributes,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueEvent {
+    pub user: User,
+    pub project: Project,
+    #[serde(rename = "object_attributes", deserialize_with = "issue_attributes")]
     pub attributes: IssueAttributes,
+    #[serde(default)]
+    pub changes: Changes,
+    #[serde(default)]
+    pub assignees: Vec<UserRef>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MergeRequestEvent {
     pub user: User,
     pub project: Project,
-    #[serde(rename = "object_attributes")]
+    #[serde(rename = "object_attributes", deserialize_with = "issue_attributes")]
     pub attributes: IssueAttributes,
+    #[serde(default)]
+    pub changes: Changes,
+    #[serde(default)]
+    pub assignees: Vec<UserRef>,
+    #[serde(default)]
+    pub reviewers: Vec<UserRef>,
+}
+
+impl IssueEvent {
+    pub fn web_url(&self) -> String {
+        self.attributes.url.clone()
+            .unwrap_or_else(|| format!("{}/-/issues/{}", self.project.web_url, self.attributes.issue_id))
+    }
+}
+
+impl MergeRequestEvent {
+    pub fn web_url(&self) -> String {
+        self.attributes.url.clone()
+            .unwrap_or_else(|| format!("{}/-/merge_requests/{}", self.project.web_url, self.attributes.issue_id))
+    }
+
+    /// The action of the event, with updates that mark the merge request as ready reported as `ready`.
+    pub fn action(&self) -> &str {
+        if self.attributes.action == "update" && self.changes.marked_ready() {
+            "ready"
+        } else {
+            &self.attributes.action
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -85,6 +498,41 @@ pub struct PipelineEvent {
     pub commit: Commit,
     #[serde(rename = "object_attributes")]
     pub attributes: PipelineAttributes,
+    #[serde(default)]
+    pub builds: Vec<Build>,
+    pub merge_request: Option<PipelineMergeRequest>,
+}
+
+impl PipelineEvent {
+    pub fn web_url(&self) -> String {
+        format!("{}/-/pipelines/{}", self.project.web_url, self.attributes.id)
+    }
+
+    pub fn merge_request_url(&self, mr: &PipelineMergeRequest) -> String {
+        mr.url.clone()
+            .unwrap_or_else(|| format!("{}/-/merge_requests/{}", self.project.web_url, mr.iid))
+    }
+
+    /// Builds grouped by stage, in the order the stages run.
+    pub fn stages(&self) -> Vec<(&str, Vec<&Build>)> {
+        let mut stages = self.attributes.stages.iter()
+            .map(|stage| (stage.as_str(), Vec::new()))
+            .collect::<Vec<_>>();
+
+        for build in &self.builds {
+            match stages.iter_mut().find(|(stage, _)| *stage == build.stage) {
+                Some((_, builds)) => builds.push(build),
+                None => stages.push((&build.stage, vec![build])),
+            }
+        }
+
+        for (_, builds) in &mut stages {
+            builds.sort_by_key(|build| build.id);
+        }
+
+        stages.retain(|(_, builds)| !builds.is_empty());
+        stages
+    }
 }
 
 #[cfg(test)]
@@ -98,6 +546,73 @@ mod tests {
         json::from_str::<PushEvent>(&event).unwrap();
     }
 
+    #[test]
+    fn deserialize_tag_push_event() {
+        let event = include_str!("../../tests/data/tag_push_event.json");
+        let event = json::from_str::<TagPushEvent>(event).unwrap();
+        assert_eq!(event.tag_name(), "v1.0.0");
+        assert!(!event.is_deletion());
+    }
+
+    #[test]
+    fn deserialize_tag_deleted_event() {
+        let event = include_str!("../../tests/data/tag_deleted_event.json");
+        let event = json::from_str::<TagPushEvent>(event).unwrap();
+        assert!(event.is_deletion());
+    }
+
+    #[test]
+    fn deserialize_release_created_event() {
+        let event = include_str!("../../tests/data/release_created_event.json");
+        json::from_str::<ReleaseEvent>(event).unwrap();
+    }
+
+    #[test]
+    fn deserialize_note_events() {
+        let event = include_str!("../../tests/data/note_mr_event.json");
+        let event = json::from_str::<NoteEvent>(event).unwrap();
+        assert_eq!(event.noteable(), "merge request !4 Implement anti-cheat system");
+
+        let event = include_str!("../../tests/data/note_commit_event.json");
+        let event = json::from_str::<NoteEvent>(event).unwrap();
+        assert_eq!(event.noteable(), "commit 89e617d5");
+    }
+
+    #[test]
+    fn deserialize_job_failed_event() {
+        let event = include_str!("../../tests/data/job_failed_event.json");
+        let event = json::from_str::<JobEvent>(event).unwrap();
+        assert_eq!(event.web_url(), "https://gitlab.com/testmaster/project/-/jobs/1977");
+    }
+
+    #[test]
+    fn deserialize_deployment_event() {
+        let event = include_str!("../../tests/data/deployment_event.json");
+        json::from_str::<DeploymentEvent>(event).unwrap();
+    }
+
+    #[test]
+    fn deserialize_feature_flag_event() {
+        let event = include_str!("../../tests/data/feature_flag_event.json");
+        json::from_str::<FeatureFlagEvent>(event).unwrap();
+    }
+
+    #[test]
+    fn deserialize_wiki_page_event() {
+        let event = include_str!("../../tests/data/wiki_page_event.json");
+        json::from_str::<WikiPageEvent>(event).unwrap();
+    }
+
+    #[test]
+    fn deserialize_pipeline_failed_event() {
+        let event = include_str!("../../tests/data/pipeline_failed_event.json");
+        let event = json::from_str::<PipelineEvent>(event).unwrap();
+        let stages = event.stages().into_iter()
+            .map(|(stage, builds)| (stage, builds.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(stages, vec![("build", 1), ("test", 2), ("deploy", 1)]);
+    }
+
     #[test]
     fn deserialize_issue_opened_event() {
         let event = include_str!("../../tests/data/issue_opened_event.json");
@@ -122,9 +637,49 @@ mod tests {
         json::from_str::<MergeRequestEvent>(&event).unwrap();
     }
 
+    #[test]
+    fn deserialize_mr_updated_event() {
+        let event = include_str!("../../tests/data/mr_updated_event.json");
+        let event = json::from_str::<MergeRequestEvent>(event).unwrap();
+        assert_eq!(event.action(), "ready");
+    }
+
+    #[test]
+    fn deserialize_issue_reopened_event() {
+        let event = include_str!("../../tests/data/issue_reopened_event.json");
+        json::from_str::<IssueEvent>(event).unwrap();
+    }
+
     #[test]
     fn deserialize_mr_closed_event() {
         let event = include_str!("../../tests/data/mr_closed_event.json");
         json::from_str::<MergeRequestEvent>(&event).unwrap();
     }
+
+    #[test]
+    fn deserialize_test_events() {
+        let event = include_str!("../../tests/data/mr_test_event.json");
+        let event = json::from_str::<MergeRequestEvent>(event).unwrap();
+        assert_eq!(event.action(), "open");
+        assert_eq!(event.web_url(), "https://gitlab.com/testmaster/project/-/merge_requests/4");
+        assert_eq!(event.user.avatar_url, DEFAULT_AVATAR_URL);
+        assert!(event.project.avatar_url.is_empty());
+
+        let event = include_str!("../../tests/data/issue_test_event.json");
+        let event = json::from_str::<IssueEvent>(event).unwrap();
+        assert_eq!(event.attributes.action, "close");
+        assert_eq!(event.web_url(), "https://gitlab.com/testmaster/project/-/issues/7");
+        assert_eq!(event.user.avatar_url, DEFAULT_AVATAR_URL);
+    }
+
+    #[test]
+    fn deserialize_push_event_without_avatars() {
+        let event = include_str!("../../tests/data/push_no_avatar_event.json");
+        let event = json::from_str::<PushEvent>(event).unwrap();
+        assert_eq!(event.user_avatar, DEFAULT_AVATAR_URL);
+        assert_eq!(
+            event.commits[0].web_url(&event.project),
+            "https://gitlab.com/testmaster/project/-/commit/679ac842ad4e77a9"
+        );
+    }
 }
@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use crate::http::HeaderMap;
+
+const BUCKET_HEADER: &str = "X-RateLimit-Bucket";
+const REMAINING_HEADER: &str = "X-RateLimit-Remaining";
+const RESET_AFTER_HEADER: &str = "X-RateLimit-Reset-After";
+const GLOBAL_HEADER: &str = "X-RateLimit-Global";
+const SCOPE_HEADER: &str = "X-RateLimit-Scope";
+
+/// Limits requests to Discord according to the rate limits it reports.
+///
+/// Discord assigns every route a bucket, shared by routes with the same limit, and tells us
+/// how many requests are left in it. Requests to the same webhook are sent one at a time and
+/// in order, so the counts stay accurate and messages arrive in the order they were sent.
+#[derive(Default)]
+pub struct OutboundLimiter {
+    state: StdMutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Set after hitting the global limit, which applies to every route
+    global_until: Option<Instant>,
+    /// Bucket each route was last seen in
+    routes: HashMap<String, String>,
+    buckets: HashMap<String, Bucket>,
+    webhooks: HashMap<String, Arc<Mutex<()>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    remaining: u32,
+    reset_at: Instant,
+}
+
+/// A 429 response, as told by its headers and body.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limited {
+    pub retry_after: Duration,
+    pub global: bool,
+}
+
+impl OutboundLimiter {
+    /// Waits for the turn of a request to `uri`, the returned guard keeps other
+    /// requests to the same webhook waiting until it is dropped.
+    pub async fn acquire(&self, uri: &str) -> OwnedMutexGuard<()> {
+        let webhook = {
+            let mut state = self.state.lock().unwrap();
+            state.webhooks.entry(webhook_key(uri).to_string()).or_default().clone()
+        };
+
+        let guard = webhook.lock_owned().await;
+        self.wait(uri).await;
+        guard
+    }
+
+    /// Waits until neither the global limit nor the bucket of `uri` holds back requests.
+    pub async fn wait(&self, uri: &str) {
+        loop {
+            let delay = self.state.lock().unwrap().delay(&route_key(uri), Instant::now());
+            match delay {
+                Some(delay) => {
+                    log::debug!("Waiting {:?} for the Discord rate limit of {}", delay, route_key(uri));
+                    tokio::time::sleep(delay).await;
+                },
+                None => return,
+            }
+        }
+    }
+
+    /// Updates the bucket of `uri` from the rate limit headers of a response.
+    pub fn update(&self, uri: &str, headers: &HeaderMap) {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+        let bucket = match header(BUCKET_HEADER) {
+            Some(bucket) => bucket.to_string(),
+            None => return,
+        };
+        let remaining = header(REMAINING_HEADER).and_then(|v| v.parse().ok());
+        let reset_after = header(RESET_AFTER_HEADER).and_then(parse_seconds);
+
+        let mut state = self.state.lock().unwrap();
+        state.routes.insert(route_key(uri), bucket.clone());
+
+        if let (Some(remaining), Some(reset_after)) = (remaining, reset_after) {
+            state.buckets.insert(bucket, Bucket { remaining, reset_at: Instant::now() + reset_after });
+        }
+    }
+
+    /// Holds back requests after a 429 response.
+    pub fn limited(&self, uri: &str, limited: Limited) {
+        let mut state = self.state.lock().unwrap();
+        let until = Instant::now() + limited.retry_after;
+
+        if limited.global {
+            state.global_until = Some(until);
+            return;
+        }
+
+        let route = route_key(uri);
+        let bucket = state.routes.get(&route).cloned().unwrap_or(route);
+        state.buckets.insert(bucket, Bucket { remaining: 0, reset_at: until });
+    }
+}
+
+impl State {
+    fn delay(&mut self, route: &str, now: Instant) -> Option<Duration> {
+        if let Some(until) = self.global_until.filter(|until| *until > now) {
+            return Some(until - now);
+        }
+
+        let bucket = self.routes.get(route).map_or(route, String::as_str);
+        match self.buckets.get_mut(bucket) {
+            Some(bucket) if bucket.reset_at <= now => None,
+            Some(bucket) if bucket.remaining == 0 => Some(bucket.reset_at - now),
+            Some(bucket) => {
+                // Counted down right away, requests to other webhooks may share the bucket
+                bucket.remaining -= 1;
+                None
+            },
+            None => None,
+        }
+    }
+}
+
+impl Limited {
+    /// Reads a 429 response, preferring the precise `retry_after` of the body over the headers.
+    pub fn from_response(headers: &HeaderMap, body: &[u8]) -> Option<Self> {
+        #[derive(serde::Deserialize)]
+        struct Body {
+            retry_after: Option<f64>,
+            #[serde(default)]
+            global: bool,
+        }
+
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let body = serde_json::from_slice::<Body>(body).ok();
+
+        let retry_after = body.as_ref()
+            .and_then(|body| body.retry_after)
+            .and_then(seconds)
+            .or_else(|| header(RESET_AFTER_HEADER).and_then(parse_seconds))
+            .or_else(|| header("Retry-After").and_then(parse_seconds))?;
+
+        let global = matches!(&body, Some(body) if body.global)
+            || header(GLOBAL_HEADER) == Some("true")
+            || header(SCOPE_HEADER) == Some("global");
+
+        Some(Self { retry_after, global })
+    }
+}
+
+fn parse_seconds(value: &str) -> Option<Duration> {
+    value.parse::<f64>().ok().and_then(seconds)
+}
+
+fn seconds(secs: f64) -> Option<Duration> {
+    Some(secs)
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// The webhook a request goes to, e.g. `https://discord.com/api/webhooks/1/token`.
+fn webhook_key(uri: &str) -> &str {
+    let uri = uri.split('?').next().unwrap_or(uri);
+    uri.split("/messages/").next().unwrap_or(uri)
+}
+
+/// The route of a request, with message ids left out as they share a limit.
+fn route_key(uri: &str) -> String {
+    let path = uri.split('?').next().unwrap_or(uri);
+    match path.find("/messages/") {
+        Some(index) => format!("{}/messages/:id", &path[..index]),
+        None => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    const WEBHOOK: &str = "https://discord.com/api/webhooks/1/token";
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn exhausted_buckets_hold_back_requests() {
+        let limiter = OutboundLimiter::default();
+        limiter.update(&format!("{}?wait=true", WEBHOOK), &headers(&[
+            (BUCKET_HEADER, "abc"),
+            (REMAINING_HEADER, "1"),
+            (RESET_AFTER_HEADER, "2.5"),
+        ]));
+
+        let mut state = limiter.state.lock().unwrap();
+        let now = Instant::now();
+        assert_eq!(state.delay(WEBHOOK, now), None);
+        let delay = state.delay(WEBHOOK, now).expect("bucket is exhausted");
+        assert!(delay > Duration::from_secs(2) && delay <= Duration::from_millis(2500));
+        assert_eq!(state.delay(WEBHOOK, now + Duration::from_secs(3)), None);
+    }
+
+    #[test]
+    fn rate_limit_responses_prefer_the_body() {
+        let limited = Limited::from_response(
+            &headers(&[(RESET_AFTER_HEADER, "5"), (SCOPE_HEADER, "user")]),
+            br#"{"message": "You are being rate limited.", "retry_after": 0.25, "global": true}"#,
+        );
+        assert_eq!(limited, Some(Limited { retry_after: Duration::from_millis(250), global: true }));
+
+        let limited = Limited::from_response(&headers(&[(RESET_AFTER_HEADER, "5")]), b"");
+        assert_eq!(limited, Some(Limited { retry_after: Duration::from_secs(5), global: false }));
+
+        assert_eq!(Limited::from_response(&HeaderMap::new(), b"{}"), None);
+    }
+
+    #[test]
+    fn message_edits_share_a_route() {
+        assert_eq!(route_key(&format!("{}/messages/42?thread_id=7", WEBHOOK)), format!("{}/messages/:id", WEBHOOK));
+        assert_eq!(webhook_key(&format!("{}/messages/42?thread_id=7", WEBHOOK)), WEBHOOK);
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use crate::store::HookId;
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs deliveries one after the other for each destination, in the order they were queued.
+///
+/// Every destination gets a worker of its own, so a slow or rate limited webhook
+/// only holds back the deliveries queued behind it.
+#[derive(Default)]
+pub struct DeliveryQueues {
+    workers: HashMap<String, UnboundedSender<Job>>,
+}
+
+impl DeliveryQueues {
+    pub fn enqueue<F>(&mut self, destination: &str, job: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut job: Job = Box::pin(job);
+
+        if let Some(worker) = self.workers.get(destination) {
+            match worker.send(job) {
+                Ok(()) => return,
+                // The worker is gone, start a new one below
+                Err(mpsc::error::SendError(returned)) => job = returned,
+            }
+        }
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Job>();
+        tokio::spawn(async move {
+            while let Some(job) = receiver.recv().await {
+                job.await;
+            }
+        });
+
+        // Can't fail, the worker holding the receiver was just started
+        let _ = sender.send(job);
+        self.workers.insert(destination.to_string(), sender);
+    }
+
+    /// Stops the worker of a destination once it finished the deliveries queued so far.
+    pub fn remove(&mut self, destination: &str) {
+        self.workers.remove(destination);
+    }
+}
+
+/// The queue events of a hook wait in until its schedule opens, e.g. `held/<hook id>`.
+pub fn held_key(hook_id: &HookId) -> String {
+    format!("held/{}", hook_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::{Arc, Mutex}, time::Duration};
+
+    #[tokio::test]
+    async fn deliveries_keep_their_order() {
+        let mut queues = DeliveryQueues::default();
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+
+        for (i, delay) in [30, 0, 10].iter().enumerate() {
+            let delivered = delivered.clone();
+            queues.enqueue("https://discord.test/a", async move {
+                tokio::time::sleep(Duration::from_millis(*delay)).await;
+                delivered.lock().unwrap().push(i);
+            });
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*delivered.lock().unwrap(), vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+use serde::Serialize;
+use chrono::DateTime;
+use crate::{
+    http::{StatusCode, Response},
+    Result,
+};
+
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub window: Duration,
+    pub ip_limit: u32,
+    pub hook_limit: u32,
+    pub max_failures: u32,
+    pub lockout: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Limited {
+    RateLimited(Duration),
+    LockedOut(Duration),
+}
+
+impl Limited {
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            Self::RateLimited(d) | Self::LockedOut(d) => *d,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::RateLimited(_) => "rate limited",
+            Self::LockedOut(_) => "locked out",
+        }
+    }
+
+    pub fn into_response(self) -> Result<Response> {
+        // Round up, a zero would invite an immediate retry
+        let secs = self.retry_after().as_secs() + 1;
+
+        Ok(Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("Retry-After", secs.to_string())
+            .body("".into())?)
+    }
+}
+
+/// What a client authenticates against, failing to do so only locks it out of that.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum Target {
+    Api,
+    Hook(String),
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+enum Key {
+    Ip(IpAddr),
+    Hook(String),
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+#[derive(Debug)]
+struct Failures {
+    started: Instant,
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockedClient {
+    pub ip: IpAddr,
+    pub blocked_requests: u64,
+    pub last_reason: &'static str,
+    pub last_blocked_at: DateTime<chrono::Utc>,
+    pub locked_out: bool,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    windows: HashMap<Key, Window>,
+    failures: HashMap<(IpAddr, Target), Failures>,
+    blocked: HashMap<IpAddr, BlockedClient>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            windows: HashMap::new(),
+            failures: HashMap::new(),
+            blocked: HashMap::new(),
+        }
+    }
+
+    /// Checks the per IP limit and any active lockout of the client from `target`.
+    pub fn check_ip(&mut self, ip: IpAddr, target: &Target) -> std::result::Result<(), Limited> {
+        let now = Instant::now();
+        self.prune(now);
+
+        let result = self.check_lockout(ip, target, now)
+            .and_then(|_| self.hit(Key::Ip(ip), self.config.ip_limit, now));
+
+        if let Err(limited) = result {
+            self.record_blocked(ip, limited);
+        }
+
+        result
+    }
+
+    /// Checks the per hook limit, shared by all clients calling the hook.
+    pub fn check_hook(&mut self, ip: IpAddr, hook_id: &str) -> std::result::Result<(), Limited> {
+        let now = Instant::now();
+        let result = self.hit(Key::Hook(hook_id.to_string()), self.config.hook_limit, now);
+
+        if let Err(limited) = result {
+            self.record_blocked(ip, limited);
+        }
+
+        result
+    }
+
+    pub fn record_failure(&mut self, ip: IpAddr, target: &Target) {
+        let now = Instant::now();
+        let window = self.config.window;
+        let failures = self.failures.entry((ip, target.clone())).or_insert(Failures {
+            started: now,
+            count: 0,
+            locked_until: None,
+        });
+
+        if now.duration_since(failures.started) > window {
+            failures.started = now;
+            failures.count = 0;
+        }
+
+        failures.count += 1;
+
+        if failures.count >= self.config.max_failures {
+            failures.locked_until = Some(now + self.config.lockout);
+            log::warn!(
+                "Locking out {} from {:?} for {} seconds after {} failed authentication attempts",
+                ip, target, self.config.lockout.as_secs(), failures.count
+            );
+        }
+    }
+
+    pub fn record_success(&mut self, ip: IpAddr, target: &Target) {
+        self.failures.remove(&(ip, target.clone()));
+    }
+
+    pub fn blocked_clients(&self) -> Vec<BlockedClient> {
+        let now = Instant::now();
+
+        self.blocked.values()
+            .cloned()
+            .map(|mut client| {
+                client.locked_out = self.failures.iter()
+                    .any(|((ip, _), f)| *ip == client.ip && matches!(f.locked_until, Some(until) if until > now));
+                client
+            })
+            .collect()
+    }
+
+    fn check_lockout(&mut self, ip: IpAddr, target: &Target, now: Instant) -> std::result::Result<(), Limited> {
+        let key = (ip, target.clone());
+        if let Some(failures) = self.failures.get(&key) {
+            if let Some(until) = failures.locked_until {
+                if until > now {
+                    return Err(Limited::LockedOut(until - now));
+                }
+                self.failures.remove(&key);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn hit(&mut self, key: Key, limit: u32, now: Instant) -> std::result::Result<(), Limited> {
+        let window_length = self.config.window;
+        let window = self.windows.entry(key).or_insert(Window { started: now, count: 0 });
+
+        let elapsed = now.duration_since(window.started);
+        if elapsed >= window_length {
+            window.started = now;
+            window.count = 0;
+        }
+
+        if window.count >= limit {
+            return Err(Limited::RateLimited(window_length - now.duration_since(window.started)));
+        }
+
+        window.count += 1;
+        Ok(())
+    }
+
+    fn record_blocked(&mut self, ip: IpAddr, limited: Limited) {
+        log::warn!(
+            "Blocked request from {} ({}), retry in {} seconds",
+            ip, limited.reason(), limited.retry_after().as_secs()
+        );
+
+        let client = self.blocked.entry(ip).or_insert(BlockedClient {
+            ip,
+            blocked_requests: 0,
+            last_reason: limited.reason(),
+            last_blocked_at: chrono::Utc::now(),
+            locked_out: false,
+        });
+        client.blocked_requests += 1;
+        client.last_reason = limited.reason();
+        client.last_blocked_at = chrono::Utc::now();
+    }
+
+    fn prune(&mut self, now: Instant) {
+        if self.windows.len() > PRUNE_THRESHOLD {
+            let window = self.config.window;
+            self.windows.retain(|_, w| now.duration_since(w.started) < window);
+        }
+
+        if self.failures.len() > PRUNE_THRESHOLD {
+            let window = self.config.window;
+            self.failures.retain(|_, f| {
+                matches!(f.locked_until, Some(until) if until > now)
+                    || now.duration_since(f.started) < window
+            });
+        }
+
+        if self.blocked.len() > PRUNE_THRESHOLD {
+            let cutoff = chrono::Utc::now() - chrono::Duration::days(1);
+            self.blocked.retain(|_, c| c.last_blocked_at > cutoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            window: Duration::from_secs(60),
+            ip_limit: 3,
+            hook_limit: 2,
+            max_failures: 2,
+            lockout: Duration::from_secs(300),
+        })
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn requests_are_counted_per_window() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.hit(Key::Ip(ip(1)), 3, now).is_ok());
+        }
+        match limiter.hit(Key::Ip(ip(1)), 3, now + Duration::from_secs(20)) {
+            Err(Limited::RateLimited(retry_after)) => assert_eq!(retry_after, Duration::from_secs(40)),
+            other => panic!("expected a rate limit, got {:?}", other),
+        }
+
+        // Other clients and the next window start from zero
+        assert!(limiter.hit(Key::Ip(ip(2)), 3, now).is_ok());
+        assert!(limiter.hit(Key::Ip(ip(1)), 3, now + Duration::from_secs(60)).is_ok());
+    }
+
+    #[test]
+    fn hooks_share_their_limit_between_clients() {
+        let mut limiter = limiter();
+
+        assert!(limiter.check_hook(ip(1), "hook").is_ok());
+        assert!(limiter.check_hook(ip(2), "hook").is_ok());
+        assert!(limiter.check_hook(ip(3), "hook").is_err());
+        assert!(limiter.check_hook(ip(3), "other").is_ok());
+    }
+
+    #[test]
+    fn failures_lock_out_of_a_single_target() {
+        let mut limiter = limiter();
+        let hook = Target::Hook("hook".into());
+        let other = Target::Hook("other".into());
+
+        limiter.record_failure(ip(1), &hook);
+        assert!(limiter.check_ip(ip(1), &hook).is_ok());
+        limiter.record_failure(ip(1), &hook);
+
+        match limiter.check_ip(ip(1), &hook) {
+            Err(limited @ Limited::LockedOut(_)) => {
+                assert!(limited.retry_after() > Duration::from_secs(290));
+                assert!(limited.retry_after() <= Duration::from_secs(300));
+            },
+            other => panic!("expected a lockout, got {:?}", other),
+        }
+
+        // Hooks behind the same address keep working
+        assert!(limiter.check_ip(ip(1), &other).is_ok());
+        assert!(limiter.check_ip(ip(1), &Target::Api).is_ok());
+        assert!(limiter.blocked_clients().iter().any(|client| client.ip == ip(1) && client.locked_out));
+    }
+
+    #[test]
+    fn successes_reset_failures() {
+        let mut limiter = limiter();
+
+        limiter.record_failure(ip(1), &Target::Api);
+        limiter.record_success(ip(1), &Target::Api);
+        limiter.record_failure(ip(1), &Target::Api);
+        assert!(limiter.check_ip(ip(1), &Target::Api).is_ok());
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        let res = Limited::RateLimited(Duration::from_millis(1500)).into_response().unwrap();
+
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["Retry-After"], "2");
+    }
+}
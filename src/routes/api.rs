@@ -1,23 +1,43 @@
-use bytes::Buf;
+use std::convert::TryFrom;
 use routerify::ext::RequestExt;
 use crate::{
-    models::CreateHookConfig,
-    http::{StatusCode, Request, Response},
-    store::HookConfig,
+    models::{CreateHookConfig, UserMapping, TestEvent, RenderRequest},
+    http::{self, StatusCode, Request, Response, BodyLimits},
+    store::{HookConfig, HookId},
+    access::AccessConfig,
+    ratelimit::Target,
+    queue,
+    routes,
     Result,
     Users,
     HookRegistry,
+    RateLimiter,
+    DeliveryLog,
+    MessageIndex,
+    Digests,
+    DeliveryQueues,
+    CircuitBreaker,
 };
 
 macro_rules! require_auth {
     [$req:expr] => {
+        let client_ip = $req.data::<AccessConfig>().unwrap().client_ip(&$req);
+        let limiter = $req.data::<RateLimiter>().unwrap();
+
+        if let Err(limited) = limiter.lock().await.check_ip(client_ip, &Target::Api) {
+            return limited.into_response();
+        }
+
         if !is_authorized(&$req).await {
-            log::error!("Failed to authorize user");
+            log::error!("Failed to authorize user from {}", client_ip);
+            limiter.lock().await.record_failure(client_ip, &Target::Api);
             return Ok(Response::builder()
                 .status(StatusCode::UNAUTHORIZED)
                 .header("WWW-Authenticate", "Basic")
                 .body("".into())?);
         }
+
+        limiter.lock().await.record_success(client_ip, &Target::Api);
     }
 }
 
@@ -35,11 +55,38 @@ pub async fn get_hooks(req: Request) -> Result<Response> {
     Ok(res)
 }
 
+pub async fn get_rate_limits(req: Request) -> Result<Response> {
+    require_auth!(req);
+
+    let limiter = req.data::<RateLimiter>().unwrap();
+    let blocked = limiter.lock().await.blocked_clients();
+    let json = serde_json::to_string(&blocked)?;
+
+    let res = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())?;
+
+    Ok(res)
+}
+
 pub async fn post_hook(mut req: Request) -> Result<Response> {
     require_auth!(req);
 
-    let reader = hyper::body::aggregate(&mut req).await?.reader();
-    let config: CreateHookConfig =  serde_json::from_reader(reader)?;
+    let limits = *req.data::<BodyLimits>().unwrap();
+    let body = match http::read_body(&mut req, limits.api).await {
+        Ok(body) => body,
+        Err(problem) => return problem.into_response(),
+    };
+
+    let config = match http::parse_json::<CreateHookConfig>(&body) {
+        Ok(config) => config,
+        Err(problem) => return problem.into_response(),
+    };
+
+    if let Err(problem) = config.validate() {
+        return problem.into_response();
+    }
+
     let config: HookConfig = config.into();
 
     let json = serde_json::to_string(&config)?;
@@ -60,14 +107,204 @@ pub async fn delete_hook(req: Request) -> Result<Response> {
     let id = req.param("id").expect("id parameter");
     let hooks = req.data::<HookRegistry>().unwrap();
     let mut hooks = hooks.write().await;
+    let hook_config = hooks.get(&**id).await?;
     hooks.delete(&**id).await?;
 
+    // Other hooks may still post to the same webhook
+    let shared = hooks.all().await.iter().any(|hook| hook.discord_url == hook_config.discord_url);
+    drop(hooks);
+
+    let hook_id = HookId::try_from(&**id)?;
+    let deliveries = req.data::<DeliveryLog>().unwrap();
+    deliveries.lock().await.delete(&hook_id).await?;
+
+    let messages = req.data::<MessageIndex>().unwrap();
+    messages.lock().await.delete(&hook_id).await?;
+
+    let digests = req.data::<Digests>().unwrap();
+    digests.lock().await.delete(&hook_id).await?;
+
+    let queues = req.data::<DeliveryQueues>().unwrap();
+    let mut queues = queues.lock().await;
+    queues.remove(&queue::held_key(&hook_id));
+    if !shared {
+        queues.remove(&hook_config.discord_url);
+    }
+
+    Ok(Response::default())
+}
+
+pub async fn enable_hook(req: Request) -> Result<Response> {
+    require_auth!(req);
+
+    let id = req.param("id").expect("id parameter");
+    let hooks = req.data::<HookRegistry>().unwrap();
+    let hook_config = hooks.write().await.enable(&**id).await?;
+
+    let breaker = req.data::<CircuitBreaker>().unwrap();
+    breaker.lock().await.reset(&hook_config.discord_url);
+    log::info!("Enabled hook {} again", hook_config.id);
+
+    let json = serde_json::to_string(&hook_config)?;
+    let res = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())?;
+
+    Ok(res)
+}
+
+pub async fn test_hook(mut req: Request) -> Result<Response> {
+    require_auth!(req);
+
+    let limits = *req.data::<BodyLimits>().unwrap();
+    let body = match http::read_body(&mut req, limits.hooks).await {
+        Ok(body) => body,
+        Err(problem) => return problem.into_response(),
+    };
+
+    let test = match http::parse_json::<TestEvent>(&body) {
+        Ok(test) => test,
+        Err(problem) => return problem.into_response(),
+    };
+
+    let payload = match test.payload() {
+        Ok(payload) => payload,
+        Err(problem) => return problem.into_response(),
+    };
+
+    let id = req.param("id").expect("id parameter");
+    let hooks = req.data::<HookRegistry>().unwrap();
+    let hook_config = hooks.read().await.get(&**id).await?;
+    log::info!("Sending test {} through hook {}", test.event, hook_config.id);
+
+    routes::hooks::accept_event(req, hook_config, &test.event, &payload, None, true).await
+}
+
+pub async fn render(mut req: Request) -> Result<Response> {
+    require_auth!(req);
+
+    let limits = *req.data::<BodyLimits>().unwrap();
+    let body = match http::read_body(&mut req, limits.hooks).await {
+        Ok(body) => body,
+        Err(problem) => return problem.into_response(),
+    };
+
+    let render = match http::parse_json::<RenderRequest>(&body) {
+        Ok(render) => render,
+        Err(problem) => return problem.into_response(),
+    };
+
+    let payload = match render.payload() {
+        Ok(payload) => payload,
+        Err(problem) => return problem.into_response(),
+    };
+
+    let hook_config: HookConfig = match &render.hook {
+        Some(id) => {
+            let hooks = req.data::<HookRegistry>().unwrap();
+            hooks.read().await.get(&*id.to_string()).await?
+        },
+        None => CreateHookConfig::default().into(),
+    };
+
+    let messages = match routes::hooks::render_messages(&req, &hook_config, &render.event, &payload).await {
+        Ok(messages) => messages,
+        Err(err) => return err.into_problem().into_response(),
+    };
+
+    let json = serde_json::to_string(&messages)?;
+    let res = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())?;
+
+    Ok(res)
+}
+
+pub async fn get_deliveries(req: Request) -> Result<Response> {
+    require_auth!(req);
+
+    let id = req.param("id").expect("id parameter");
+    let hooks = req.data::<HookRegistry>().unwrap();
+    let hook_config = hooks.read().await.get(&**id).await?;
+
+    let deliveries = req.data::<DeliveryLog>().unwrap();
+    let history = deliveries.lock().await.history(&hook_config.id).await;
+    let json = serde_json::to_string(&history)?;
+
+    let res = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())?;
+
+    Ok(res)
+}
+
+pub async fn get_user_mappings(req: Request) -> Result<Response> {
+    require_auth!(req);
+
+    let hooks = req.data::<HookRegistry>().unwrap();
+    let mappings = hooks.read().await.user_mappings().await;
+    let json = serde_json::to_string(&mappings)?;
+
+    let res = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())?;
+
+    Ok(res)
+}
+
+pub async fn post_user_mapping(mut req: Request) -> Result<Response> {
+    require_auth!(req);
+
+    let limits = *req.data::<BodyLimits>().unwrap();
+    let body = match http::read_body(&mut req, limits.api).await {
+        Ok(body) => body,
+        Err(problem) => return problem.into_response(),
+    };
+
+    let mapping = match http::parse_json::<UserMapping>(&body) {
+        Ok(mapping) => mapping,
+        Err(problem) => return problem.into_response(),
+    };
+
+    if let Err(problem) = mapping.validate() {
+        return problem.into_response();
+    }
+
+    let json = serde_json::to_string(&mapping)?;
+    let hooks = req.data::<HookRegistry>().unwrap();
+    hooks.write().await.insert_user_mapping(mapping).await?;
+
+    let res = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())?;
+
+    Ok(res)
+}
+
+pub async fn delete_user_mapping(req: Request) -> Result<Response> {
+    require_auth!(req);
+
+    let gitlab = req.param("gitlab").expect("gitlab parameter");
+    let hooks = req.data::<HookRegistry>().unwrap();
+
+    if !hooks.write().await.delete_user_mapping(gitlab).await? {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("".into())?);
+    }
+
     Ok(Response::default())
 }
 
 async fn is_authorized(req: &Request) -> bool {
     if let Some(auth_header) = req.headers().get("Authorization") {
-        let auth_header = auth_header.to_str().unwrap();
+        let auth_header = match auth_header.to_str() {
+            Ok(h) => h,
+            Err(_) => {
+                log::error!("Authorization header is not valid ASCII");
+                return false;
+            },
+        };
         let parts = auth_header.split(' ').collect::<Vec<_>>();
 
         if let &["Basic", credentials] = &parts[..] {
@@ -97,9 +334,9 @@ fn decode_auth_header<B: AsRef<[u8]>>(data: B) -> Result<Vec<String>>{
     Ok(base64::decode(data)?
         .split(|&x| x == 0x3A)
         .map(|v| {
-            String::from_utf8(v.to_vec()).unwrap()
+            String::from_utf8(v.to_vec())
         })
-        .collect::<Vec<_>>())
+        .collect::<std::result::Result<Vec<_>, _>>()?)
 }
 
 fn hexdigest<B: AsRef<[u8]>>(data: B) -> String {
@@ -107,3 +344,15 @@ fn hexdigest<B: AsRef<[u8]>>(data: B) -> String {
     let digest = Sha256::digest(data.as_ref());
     hex::encode(digest)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_credentials_fail_to_decode() {
+        assert_eq!(decode_auth_header(base64::encode("user:pass")).unwrap(), vec!["user", "pass"]);
+        assert!(decode_auth_header(base64::encode(b"user:\xff\xfe")).is_err());
+        assert!(decode_auth_header("not base64!").is_err());
+    }
+}
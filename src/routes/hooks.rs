@@ -1,36 +1,268 @@
+use std::convert::TryFrom;
 use anyhow::{anyhow, bail};
-use bytes::Buf;
 use routerify::ext::RequestExt;
 use crate::{
-    http::{StatusCode, Request, Response, Client as HttpClient},
-    models::discord::Embed,
+    http::{self, StatusCode, Method, Request, Response, BodyLimits, Problem, Client as HttpClient},
+    models::{
+        DiscordTarget,
+        discord::{self, Embed, WebhookMessage, Title, Footer, Color},
+        gitlab::{
+            PushEvent, TagPushEvent, ReleaseEvent, NoteEvent, JobEvent, IssueEvent, MergeRequestEvent,
+            PipelineEvent, DeploymentEvent, FeatureFlagEvent, WikiPageEvent, Project,
+        },
+    },
+    access::AccessConfig,
+    ratelimit::Target,
+    error::{EventError, RenderError, DeliveryError},
+    outbound::{self, Limited},
+    filters::{NoteFilter, IssueFilter, MergeRequestFilter, JobFilter},
+    store::{HookConfig, DeliveryRecord, DeliveryStatus, DuplicatePolicy, TrackedMessage, Disabled},
+    digest::Activity,
+    breaker,
+    queue,
+    schedule::OutsidePolicy,
     Result,
     HookRegistry,
+    RateLimiter,
+    DeliveryLog,
+    JobCache,
+    MessageIndex,
+    Digests,
+    OutboundLimiter,
+    DeliveryQueues,
+    CircuitBreaker,
 };
 
 const GITLAB_EVENT_HEADER: &str = "X-Gitlab-Event";
 const GITLAB_TOKEN_HEADER: &str = "X-Gitlab-Token";
-const DISCORD_RATELIMIT_RESET_HEADER: &str = "X-RateLimit-Reset";
+const GITLAB_EVENT_UUID_HEADER: &str = "X-Gitlab-Event-UUID";
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+pub async fn post_gitlab(mut req: Request) -> Result<Response> {
+    let access = req.data::<AccessConfig>().unwrap();
+    let client_ip = access.client_ip(&req);
+    let id = req.param("id").expect("id parameter").clone();
+    let limiter = req.data::<RateLimiter>().unwrap();
+    let target = Target::Hook(id.to_string());
+
+    if let Err(limited) = limiter.lock().await.check_ip(client_ip, &target) {
+        return limited.into_response();
+    }
+
+    if !allowed_source(&req, access, client_ip).await {
+        log::warn!("Rejected request from {} not in allowlist of hook {}", client_ip, id);
+        let res = Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body("".into())?;
+        return Ok(res);
+    }
 
-pub async fn post_gitlab(req: Request) -> Result<Response> {
     if let Err(e) = valid_token(&req).await {
-        log::error!("GitLab token validation failed: {}", e);
+        log::error!("GitLab token validation failed for {}: {}", client_ip, e);
+        limiter.lock().await.record_failure(client_ip, &target);
         let res = Response::builder()
             .status(StatusCode::FORBIDDEN)
             .body("".into())?;
         return Ok(res);
     }
 
-    if let Some(event) = req.headers().get(GITLAB_EVENT_HEADER) {
-        let event = event.to_str().unwrap().to_string();
-        tokio::spawn(handle_event(req, event));
-        return Ok(Response::default());
+    {
+        let mut limiter = limiter.lock().await;
+        limiter.record_success(client_ip, &target);
+        if let Err(limited) = limiter.check_hook(client_ip, &id) {
+            return limited.into_response();
+        }
+    }
+
+    let event = match req.headers().get(GITLAB_EVENT_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(event) => event.to_string(),
+        None => {
+            return Problem::new(StatusCode::BAD_REQUEST, "Missing event header")
+                .with_detail(format!("{} header is required", GITLAB_EVENT_HEADER))
+                .into_response();
+        },
+    };
+
+    let limits = *req.data::<BodyLimits>().unwrap();
+    let payload = match http::read_body(&mut req, limits.hooks).await {
+        Ok(payload) => payload,
+        Err(problem) => {
+            log::error!("Rejected {} payload: {}", event, problem.title);
+            return problem.into_response();
+        },
+    };
+
+    let hooks = req.data::<HookRegistry>().unwrap();
+    let hook_config = hooks.read().await.get(id.as_ref()).await?;
+    let event_id = event_id(&req);
+
+    accept_event(req, hook_config, &event, &payload, event_id, false).await
+}
+
+/// Filters, renders and queues an event for delivery, the way events received from GitLab are.
+///
+/// Test events are delivered like any other, but leave the summaries, held jobs and failure counts alone.
+pub async fn accept_event(req: Request, hook_config: HookConfig, event: &str, payload: &[u8], event_id: Option<String>, test: bool) -> Result<Response> {
+    let mut record = DeliveryRecord::new(event, event_id);
+    record.test = test;
+    let deliveries = req.data::<DeliveryLog>().unwrap();
+    let mut deliveries = deliveries.lock().await;
+    let redelivery = match &record.event_id {
+        Some(event_id) => deliveries.is_duplicate(&hook_config.id, event_id).await,
+        None => false,
+    };
+    let digests = req.data::<Digests>().unwrap();
+
+    if let Some(disabled) = &hook_config.disabled {
+        log::debug!("Ignoring {} for disabled hook {}", event, hook_config.id);
+        record.status = DeliveryStatus::Ignored;
+        record.detail = Some(format!("Hook is disabled: {}", disabled.reason));
+        deliveries.record(&hook_config.id, record).await?;
+        return status_response(StatusCode::NO_CONTENT);
+    }
+
+    if let Some(event_id) = &record.event_id {
+        if redelivery {
+            record.redelivery = true;
+
+            if hook_config.duplicates == DuplicatePolicy::Skip {
+                log::info!("Skipping redelivered {} {} for hook {}", event, event_id, hook_config.id);
+                record.status = DeliveryStatus::Duplicate;
+                deliveries.record(&hook_config.id, record).await?;
+                return status_response(StatusCode::NO_CONTENT);
+            }
+
+            log::info!("Delivering redelivered {} {} for hook {} again", event, event_id, hook_config.id);
+        }
     }
 
-    let res = Response::builder()
-        .status(StatusCode::BAD_REQUEST)
-        .body("".into())?;
-    Ok(res)
+    let jobs = req.data::<JobCache>().unwrap();
+    let rendered = match render_event(&hook_config, jobs, event, payload).await {
+        Ok(rendered) => rendered,
+        Err(err) => {
+            log::error!("Rejected {} for hook {}: {}", event, hook_config.id, err);
+            record.status = DeliveryStatus::Failed;
+            record.detail = Some(err.to_string());
+            deliveries.record(&hook_config.id, record).await?;
+            return err.into_problem().into_response();
+        },
+    };
+
+    match rendered.jobs {
+        _ if test => {},
+        // Redelivered jobs are held back already
+        Some(JobChange::Hold { pipeline_id, line }) if !redelivery => {
+            jobs.lock().await.push(&hook_config.id, pipeline_id, line).await;
+        },
+        Some(JobChange::Release { pipeline_id }) => {
+            jobs.lock().await.remove(&hook_config.id, pipeline_id).await;
+        },
+        _ => {},
+    }
+
+    // Redelivered events were counted the first time around
+    if let (Some(activity), Some(_), false) = (rendered.activity, hook_config.summary.period, redelivery || test) {
+        if let Err(err) = digests.lock().await.record(&hook_config.id, activity).await {
+            log::error!("Failed to record activity for hook {}: {}", hook_config.id, err);
+        }
+    }
+
+    let outgoing = match rendered.outgoing {
+        Some(outgoing) => outgoing,
+        None => {
+            record.status = DeliveryStatus::Ignored;
+            deliveries.record(&hook_config.id, record).await?;
+            return status_response(StatusCode::NO_CONTENT);
+        },
+    };
+
+    // Updated messages and threads need the event on its own
+    let standalone = outgoing.tracked.is_none() && outgoing.thread.is_none();
+    let mut opens = None;
+
+    if let Some(schedule) = &hook_config.schedule {
+        let now = chrono::Utc::now();
+        if !schedule.is_open(now) {
+            match (schedule.outside, schedule.next_open(now)) {
+                (OutsidePolicy::Drop, _) | (_, None) => {
+                    record.status = DeliveryStatus::Ignored;
+                    record.detail = Some("Outside the schedule of the hook".into());
+                    deliveries.record(&hook_config.id, record).await?;
+                    return status_response(StatusCode::NO_CONTENT);
+                },
+                (OutsidePolicy::Digest, Some(time)) if standalone => {
+                    digests.lock().await.push(&hook_config.id, record.id, event, &outgoing.embed, Some(time)).await?;
+                    record.status = DeliveryStatus::Batched;
+                    deliveries.record(&hook_config.id, record).await?;
+                    return status_response(StatusCode::ACCEPTED);
+                },
+                (_, Some(time)) => {
+                    log::info!("Holding {} for hook {} until {}", event, hook_config.id, time);
+                    record.detail = Some(format!("Held until {}", time.to_rfc3339()));
+                    opens = Some(time);
+                },
+            }
+        }
+    }
+
+    if opens.is_none() && standalone && hook_config.digest.collects(event) {
+        digests.lock().await.push(&hook_config.id, record.id, event, &outgoing.embed, None).await?;
+        record.status = DeliveryStatus::Batched;
+        deliveries.record(&hook_config.id, record).await?;
+        return status_response(StatusCode::ACCEPTED);
+    }
+
+    let record_id = record.id;
+    deliveries.record(&hook_config.id, record).await?;
+
+    // Queued while the delivery log is still locked, so events are delivered in the order they were received
+    let shared_queues = req.data::<DeliveryQueues>().unwrap().clone();
+    let mut queues = shared_queues.lock().await;
+    drop(deliveries);
+
+    let destination = hook_config.discord_url.clone();
+    let held = queue::held_key(&hook_config.id);
+    let delivery = handle_event(req, hook_config, outgoing, record_id, test);
+
+    match opens {
+        // Held in a queue of the hook, so other hooks posting to the webhook aren't held back
+        Some(opens) => {
+            let shared_queues = shared_queues.clone();
+            queues.enqueue(&held, async move {
+                let delay = (opens - chrono::Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(delay).await;
+                shared_queues.lock().await.enqueue(&destination, delivery);
+            })
+        },
+        None => queues.enqueue(&destination, delivery),
+    }
+
+    status_response(StatusCode::ACCEPTED)
+}
+
+fn status_response(status: StatusCode) -> Result<Response> {
+    Ok(Response::builder()
+        .status(status)
+        .body("".into())?)
+}
+
+async fn allowed_source(req: &Request, access: &AccessConfig, client_ip: std::net::IpAddr) -> bool {
+    let id = req.param("id").expect("id parameter");
+    let hooks = req.data::<HookRegistry>().unwrap();
+    let hooks = hooks.read().await;
+    let allowed_ips = hooks.get(id.as_ref()).await
+        .map(|config| config.allowed_ips)
+        .unwrap_or_default();
+
+    access.is_allowed(client_ip, &allowed_ips)
+}
+
+fn event_id(req: &Request) -> Option<String> {
+    req.headers()
+        .get(GITLAB_EVENT_UUID_HEADER)
+        .or_else(|| req.headers().get(IDEMPOTENCY_KEY_HEADER))
+        .and_then(|v| v.to_str().ok())
+        .map(ToString::to_string)
 }
 
 async fn valid_token(req: &Request) -> Result<()> {
@@ -42,8 +274,7 @@ async fn valid_token(req: &Request) -> Result<()> {
     let remote_token = req.headers()
         .get(GITLAB_TOKEN_HEADER)
         .ok_or_else(|| anyhow!("Token header missing"))?
-        .to_str()
-        .unwrap();
+        .to_str()?;
 
     if hook_config.gitlab_token != remote_token {
         bail!("Invalid token")
@@ -52,113 +283,634 @@ async fn valid_token(req: &Request) -> Result<()> {
     }
 }
 
-async fn handle_event(mut req: Request, event: String) {
-    let payload = hyper::body::aggregate(&mut req).await.unwrap();
+async fn handle_event(req: Request, hook_config: HookConfig, outgoing: Outgoing, record_id: uuid::Uuid, test: bool) {
+    let (disabled, mentions) = {
+        let hooks = req.data::<HookRegistry>().unwrap();
+        let hooks = hooks.read().await;
+        // The hook may have been disabled by the deliveries queued before this one
+        let disabled = hooks.get(&*hook_config.id.to_string()).await.ok().and_then(|config| config.disabled);
+        (disabled, hooks.resolve_mentions(&outgoing.mentions).await)
+    };
+
+    let (status, detail) = match disabled {
+        Some(disabled) => (DeliveryStatus::Ignored, Some(format!("Hook is disabled: {}", disabled.reason))),
+        None => {
+            let result = deliver(&req, &hook_config, outgoing, &mentions).await;
+            let breaker = req.data::<CircuitBreaker>().unwrap();
+            // Only real traffic decides whether a webhook is dead
+            let opened = if test { None } else { record_outcome(breaker, &hook_config, &result).await };
+
+            match result {
+                Ok(()) => (DeliveryStatus::Delivered, None),
+                Err(err) => {
+                    let err = EventError::from(err);
+                    log::error!("Failed to deliver event for hook {}: {}", hook_config.id, err);
+                    if let Some(failures) = opened {
+                        let hooks = req.data::<HookRegistry>().unwrap();
+                        let client = req.data::<HttpClient>().unwrap();
+                        let limiter = req.data::<OutboundLimiter>().unwrap();
+                        let reason = format!("{} deliveries in a row failed, last with {}", failures, err);
+                        disable_destination(hooks, breaker, client, limiter, &hook_config, reason).await;
+                    }
+                    (DeliveryStatus::Failed, Some(err.to_string()))
+                },
+            }
+        },
+    };
+
+    let deliveries = req.data::<DeliveryLog>().unwrap();
+    let mut deliveries = deliveries.lock().await;
+    if let Err(err) = deliveries.update(&hook_config.id, record_id, status, detail).await {
+        log::error!("Failed to update delivery history: {}", err);
+    }
+}
+
+/// Counts the outcome of a delivery towards the circuit of its webhook,
+/// returning the number of failures in a row if this one opened it.
+pub async fn record_outcome(breaker: &CircuitBreaker, hook_config: &HookConfig, result: &std::result::Result<(), DeliveryError>) -> Option<u32> {
+    match result {
+        Ok(()) => breaker.lock().await.record(&hook_config.discord_url, true),
+        Err(err) if breaker::is_dead_destination(err) => breaker.lock().await.record(&hook_config.discord_url, false),
+        // Failures unrelated to the webhook neither count nor break a streak
+        Err(_) => None,
+    }
+}
+
+/// Disables the hooks posting to a failing webhook and tells the admin webhook, if configured.
+pub async fn disable_destination(hooks: &HookRegistry, breaker: &CircuitBreaker, client: &HttpClient, limiter: &outbound::OutboundLimiter, hook_config: &HookConfig, reason: String) {
+    let disabled = Disabled { reason, disabled_at: chrono::Utc::now() };
+    let disabled_hooks = match hooks.write().await.disable_destination(&hook_config.discord_url, disabled.clone()).await {
+        Ok(disabled_hooks) => disabled_hooks,
+        Err(err) => {
+            log::error!("Failed to disable hook {}: {}", hook_config.id, err);
+            return;
+        },
+    };
+
+    for hook in &disabled_hooks {
+        log::error!("Disabled hook {}: {}", hook.id, disabled.reason);
+    }
+
+    let admin_url = match breaker.lock().await.admin_url() {
+        Some(admin_url) if !disabled_hooks.is_empty() => admin_url.to_string(),
+        _ => return,
+    };
+
+    let mut embed = Embed::new(
+        Title::new(format!("Disabled {}", discord::plural(disabled_hooks.len() as u64, "hook"))),
+        Color::BAD,
+        Footer::new(discord::truncate(&disabled.reason, Footer::MAX_LENGTH), String::new()),
+    );
+    let lines = disabled_hooks.iter()
+        .map(|hook| format!("`{}` {}", hook.id, discord::escape_markdown(&hook.description)))
+        .collect::<Vec<_>>();
+    embed.append_section("Enable them again with POST /api/hook/:id/enable", &lines);
+
+    let notify = async {
+        for message in WebhookMessage::split(vec![embed], None, None) {
+            send(client, limiter, Method::POST, admin_url.clone(), to_json(&message)?).await?;
+        }
+        Ok::<_, DeliveryError>(())
+    };
+
+    if let Err(err) = notify.await {
+        log::error!("Failed to notify the admin webhook: {}", err);
+    }
+}
+
+/// A rendered event, along with the message it keeps up to date, the
+/// thread it belongs to and whom to mention, if any.
+struct Outgoing {
+    embed: Embed,
+    tracked: Option<Tracked>,
+    thread: Option<Thread>,
+    /// GitLab usernames and emails of the people to mention, looked up right before delivery
+    mentions: Vec<String>,
+}
+
+/// The outcome of rendering an event.
+struct Rendered {
+    /// `None` for events that should not be delivered
+    outgoing: Option<Outgoing>,
+    /// Counted towards the scheduled summary, whether the event is delivered or not
+    activity: Option<Activity>,
+    /// Applied to the [`JobCache`] once the event is accepted, rendering leaves it as it is
+    jobs: Option<JobChange>,
+}
+
+enum JobChange {
+    /// Hold back the summary line of a failed job until its pipeline finishes
+    Hold { pipeline_id: u64, line: String },
+    /// Forget the jobs of a finished pipeline, they are part of its message
+    Release { pipeline_id: u64 },
+}
+
+struct Tracked {
+    /// Identifies the message in the [`MessageIndex`], e.g. `pipeline/31`
+    key: String,
+    /// Whether this is the final state of the message
+    finished: bool,
+}
+
+/// The thread of a merge request or issue.
+struct Thread {
+    /// Identifies the thread in the [`MessageIndex`], e.g. `group/project!4`
+    key: String,
+    /// Name of the thread, if it has to be created
+    name: String,
+    /// Whether the merge request or issue is done, so the thread is forgotten
+    closes: bool,
+}
+
+impl Thread {
+    const NAME_MAX_LENGTH: usize = 100;
+
+    fn new(project: &Project, reference: String, title: &str, closes: bool) -> Self {
+        let name = discord::truncate(&format!("{} {}", reference, title), Self::NAME_MAX_LENGTH);
+
+        Self {
+            key: format!("{}{}", project.path_with_namespace, reference),
+            name,
+            closes,
+        }
+    }
+
+    fn merge_request(project: &Project, iid: u64, title: &str, closes: bool) -> Self {
+        Self::new(project, format!("!{}", iid), title, closes)
+    }
+
+    fn issue(project: &Project, iid: u64, title: &str, closes: bool) -> Self {
+        Self::new(project, format!("#{}", iid), title, closes)
+    }
+}
+
+/// Renders an event into the messages it would be delivered as, without delivering them.
+pub async fn render_messages(req: &Request, hook_config: &HookConfig, event: &str, payload: &[u8]) -> std::result::Result<Vec<WebhookMessage>, EventError> {
+    let jobs = req.data::<JobCache>().unwrap();
+    let outgoing = match render_event(hook_config, jobs, event, payload).await?.outgoing {
+        Some(outgoing) => outgoing,
+        None => return Ok(Vec::new()),
+    };
+
+    let hooks = req.data::<HookRegistry>().unwrap();
+    let mentions = hooks.read().await.resolve_mentions(&outgoing.mentions).await;
+    let mut messages = webhook_messages(hook_config, outgoing.embed, &mentions);
+    if let (Some(first), Some(thread)) = (messages.first_mut(), &outgoing.thread) {
+        first.thread_name = Some(thread.name.clone());
+    }
 
-    let result = match &*event {
-        "Push Hook" => handle_push_hook(payload).await,
-        "Issue Hook" => handle_issue_hook(payload).await,
-        "Merge Request Hook" => handle_merge_request_hook(payload).await,
-        "Pipeline Hook" => handle_pipeline_hook(payload).await,
+    Ok(messages)
+}
+
+/// Parses and filters an event.
+async fn render_event(hook_config: &HookConfig, jobs: &JobCache, event: &str, payload: &[u8]) -> std::result::Result<Rendered, EventError> {
+    let filters = &hook_config.filters;
+    let mut tracked = None;
+    let mut thread = None;
+    let mut activity = None;
+    let mut job_change = None;
+    // GitLab usernames and emails of the people the event is relevant for
+    let mut mentions = Vec::new();
+
+    let embed = match event {
+        "Push Hook" => {
+            let event = http::parse_json::<PushEvent>(payload)?;
+            activity = Some(Activity::Push { commits: event.total_commits_count });
+            handle_push_hook(event)?
+        },
+        "Tag Push Hook" => handle_tag_push_hook(http::parse_json(payload)?)?,
+        "Release Hook" => handle_release_hook(http::parse_json(payload)?)?,
+        "Note Hook" => {
+            let event = http::parse_json::<NoteEvent>(payload)?;
+            thread = match (&*event.attributes.noteable_type, &event.merge_request, &event.issue) {
+                ("MergeRequest", Some(mr), _) => Some(Thread::merge_request(&event.project, mr.iid, &mr.title, false)),
+                ("Issue", _, Some(issue)) => Some(Thread::issue(&event.project, issue.iid, &issue.title, false)),
+                _ => None,
+            };
+            handle_note_hook(event, &filters.notes)?
+        },
+        "Issue Hook" => {
+            let event = http::parse_json::<IssueEvent>(payload)?;
+            let attributes = &event.attributes;
+            let closes = attributes.action == "close";
+            if closes {
+                activity = Some(Activity::IssueClosed);
+            }
+            thread = Some(Thread::issue(&event.project, attributes.issue_id, &attributes.title, closes));
+            mentions.extend(event.assignees.iter().map(|user| user.username.clone()));
+            handle_issue_hook(event, &filters.issues)?
+        },
+        "Merge Request Hook" => {
+            let event = http::parse_json::<MergeRequestEvent>(payload)?;
+            let attributes = &event.attributes;
+            let closes = matches!(&*attributes.action, "merge" | "close");
+            if attributes.action == "merge" {
+                activity = Some(Activity::MergeRequestMerged);
+            }
+            thread = Some(Thread::merge_request(&event.project, attributes.issue_id, &attributes.title, closes));
+            mentions.extend(event.assignees.iter().chain(&event.reviewers).map(|user| user.username.clone()));
+            handle_merge_request_hook(event, &filters.merge_requests)?
+        },
+        "Job Hook" => {
+            let event = http::parse_json::<JobEvent>(payload)?;
+            let filter = &filters.jobs;
+            if filter.matches(&event) && filter.combine_with_pipeline {
+                job_change = Some(JobChange::Hold {
+                    pipeline_id: event.pipeline_id,
+                    line: discord::job_summary_line(&event),
+                });
+            }
+            handle_job_hook(event, filter)?
+        },
+        "Pipeline Hook" => {
+            let event = http::parse_json::<PipelineEvent>(payload)?;
+            if filters.pipelines.live_updates {
+                tracked = Some(Tracked {
+                    key: format!("pipeline/{}", event.attributes.id),
+                    finished: pipeline_finished(&event),
+                });
+            }
+            thread = event.merge_request.as_ref()
+                .map(|mr| Thread::merge_request(&event.project, mr.iid, &mr.title, false));
+            if let "success" | "failed" = &*event.attributes.status {
+                activity = Some(Activity::Pipeline { success: event.attributes.status == "success" });
+                job_change = Some(JobChange::Release { pipeline_id: event.attributes.id });
+            }
+            if event.attributes.status == "failed" {
+                // Either may be mapped, duplicates are left out when resolving them
+                mentions.extend(event.commit.author.email.clone());
+                mentions.push(event.user.username.clone());
+            }
+            handle_pipeline_hook(event, hook_config, jobs).await?
+        },
+        "Deployment Hook" => handle_deployment_hook(http::parse_json(payload)?)?,
+        "Feature Flag Hook" => handle_feature_flag_hook(http::parse_json(payload)?)?,
+        "Wiki Page Hook" => handle_wiki_page_hook(http::parse_json(payload)?)?,
         _ => {
             log::debug!("Received unhandled event {}", event);
-            return;
+            None
         },
     };
 
-    match result {
-        Ok(Some(embed)) => {
-            let id = req.param("id").unwrap();
-            let hooks = req.data::<HookRegistry>().unwrap();
-            let hooks = hooks.read().await;
-            let hook_config = hooks.get(id.as_ref()).await.unwrap();
-            let uri = &hook_config.discord_url;
-
-            log::debug!("{:#?}", embed);
-
-            let payload = serde_json::json!({ "embeds": [&embed] });
-            let json = serde_json::to_string(&payload).unwrap();
-
-            'retry: loop {
-                let client = req.data::<HttpClient>().unwrap();
-                match client.post(uri, json.clone()).await {
-                    Err(err) => log::error!("{}", err),
-                    Ok(res) if res.status() == StatusCode::TOO_MANY_REQUESTS => {
-                        let reset_secs = res.headers()
-                            .get(DISCORD_RATELIMIT_RESET_HEADER).unwrap()
-                            .to_str().unwrap()
-                            .parse().unwrap();
-                        let reset_time = std::time::Duration::from_secs_f64(reset_secs);
-                        log::warn!("Reached Discord rate limit, reset in {} seconds", reset_time.as_secs());
-                        tokio::time::sleep(reset_time).await;
-                        continue 'retry;
-                    },
-                    Ok(res) if res.status().is_client_error() => {
-                        log::error!("Headers:\n{:#?}", res.headers());
-                        let buf = hyper::body::aggregate(res).await.unwrap();
-                        let reader = buf.reader();
-                        let json: serde_json::Value = serde_json::from_reader(reader).unwrap();
-                        log::error!("Response Payload:\n{:?}", json);
-                    },
-                    Ok(_) => ()
-                }
-
-                break
+    if !hook_config.threads {
+        thread = None;
+    }
+
+    let outgoing = embed.map(|embed| Outgoing { embed, tracked, thread, mentions });
+    Ok(Rendered { outgoing, activity, jobs: job_change })
+}
+
+async fn deliver(req: &Request, hook_config: &HookConfig, outgoing: Outgoing, mentions: &[DiscordTarget]) -> std::result::Result<(), DeliveryError> {
+    let uri = &hook_config.discord_url;
+    let client = req.data::<HttpClient>().unwrap();
+    let limiter = req.data::<OutboundLimiter>().unwrap();
+
+    log::debug!("{:#?}", outgoing.embed);
+
+    let mut messages = webhook_messages(hook_config, outgoing.embed, mentions).into_iter();
+
+    if outgoing.tracked.is_none() && outgoing.thread.is_none() {
+        for message in messages {
+            send(client, limiter, Method::POST, uri.clone(), to_json(&message)?).await?;
+        }
+        return Ok(())
+    }
+
+    let mut first = messages.next()
+        .ok_or_else(|| DeliveryError::InvalidMessage("the embed was split into no messages".into()))?;
+    let rest = messages.collect::<Vec<_>>();
+
+    // Only locked to look up and store messages, deliveries of a hook already go out one at a time
+    let index = req.data::<MessageIndex>().unwrap();
+
+    if let Some(tracked) = &outgoing.tracked {
+        // Edits replace a single message, anything beyond it would pile up with every update
+        if !rest.is_empty() {
+            log::warn!("Dropping {} continuation messages of {} for hook {}", rest.len(), tracked.key, hook_config.id);
+        }
+
+        let message = index.lock().await.get(&hook_config.id, &tracked.key).await;
+        if let Some(message) = message {
+            if message.finished && !tracked.finished {
+                log::debug!("Skipping stale update of {} for hook {}", tracked.key, hook_config.id);
+                return Ok(())
             }
+
+            let query = message.thread_id.as_ref().map(|id| format!("thread_id={}", id));
+            let edit_uri = webhook_uri(uri, &format!("/messages/{}", message.message_id), query.as_deref());
+            let edit = serde_json::json!({
+                "content": &first.content,
+                "embeds": &first.embeds,
+                "allowed_mentions": &first.allowed_mentions,
+            }).to_string();
+            match send(client, limiter, Method::PATCH, edit_uri, edit).await {
+                Ok(_) => {
+                    let message = TrackedMessage::new(message.message_id, message.thread_id, tracked.finished);
+                    store_message(index, hook_config, &tracked.key, message).await;
+                    return Ok(())
+                },
+                // The message was deleted in the meantime, post a new one instead
+                Err(DeliveryError::Rejected { status: StatusCode::NOT_FOUND, .. }) => {
+                    log::warn!("Message of {} for hook {} is gone, posting a new one", tracked.key, hook_config.id);
+                },
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    let mut starter = None;
+    if let Some(thread) = &outgoing.thread {
+        starter = index.lock().await.get(&hook_config.id, &thread.key).await;
+        if starter.is_none() {
+            first.thread_name = Some(thread.name.clone());
+        }
+    }
+
+    let mut thread_id = starter.as_ref().and_then(|starter| starter.thread_id.clone());
+
+    let query = match &thread_id {
+        Some(id) => format!("wait=true&thread_id={}", id),
+        None => "wait=true".to_string(),
+    };
+
+    let res = match send(client, limiter, Method::POST, webhook_uri(uri, "", Some(&query)), to_json(&first)?).await {
+        // The thread was deleted, forget it so the next event starts a new one
+        Err(err @ DeliveryError::Rejected { status: StatusCode::NOT_FOUND, .. }) if thread_id.is_some() => {
+            if let Some(thread) = &outgoing.thread {
+                log::warn!("Thread of {} for hook {} is gone", thread.key, hook_config.id);
+                forget_message(index, hook_config, &thread.key).await;
+            }
+            return Err(err)
         },
-        Err(err) => log::error!("{}", err),
-        _ => (),
+        res => res?,
+    };
+
+    let body = hyper::body::to_bytes(res).await
+        .map_err(|e| DeliveryError::InvalidResponse(format!("failed to read body: {}", e)))?;
+    let message = serde_json::from_slice::<discord::Message>(&body)
+        .map_err(|e| DeliveryError::InvalidResponse(format!("failed to parse message: {}", e)))?;
+
+    if let Some(thread) = &outgoing.thread {
+        if thread.closes {
+            forget_message(index, hook_config, &thread.key).await;
+        } else {
+            // Messages starting a thread are posted into it, its id is the thread's channel
+            let starter = match starter {
+                Some(starter) => TrackedMessage::new(starter.message_id, starter.thread_id, false),
+                None => {
+                    thread_id = Some(message.channel_id.clone());
+                    TrackedMessage::new(message.id.clone(), thread_id.clone(), false)
+                },
+            };
+            // Stored again on every event, so active threads are not pruned
+            store_message(index, hook_config, &thread.key, starter).await;
+        }
+    }
+
+    if let Some(tracked) = &outgoing.tracked {
+        let message = TrackedMessage::new(message.id, thread_id, tracked.finished);
+        store_message(index, hook_config, &tracked.key, message).await;
+        return Ok(())
+    }
+
+    let rest_uri = match &thread_id {
+        Some(id) => webhook_uri(uri, "", Some(&format!("thread_id={}", id))),
+        None => uri.clone(),
+    };
+    for message in rest {
+        send(client, limiter, Method::POST, rest_uri.clone(), to_json(&message)?).await?;
     }
+
+    Ok(())
 }
 
-async fn handle_push_hook(payload: impl bytes::Buf) -> Result<Option<Embed>> {
-    use crate::models::gitlab::PushEvent;
+/// Posts embeds that are not tied to a single event, such as digests and summaries.
+pub async fn post_embeds(client: &HttpClient, limiter: &outbound::OutboundLimiter, hook_config: &HookConfig, embeds: Vec<Embed>) -> std::result::Result<(), DeliveryError> {
+    let messages = WebhookMessage::split(embeds, hook_config.username.clone(), hook_config.avatar_url.clone());
+
+    for message in messages {
+        send(client, limiter, Method::POST, hook_config.discord_url.clone(), to_json(&message)?).await?;
+    }
 
-    let reader = payload.reader();
-    let event: PushEvent = serde_json::from_reader(reader)?;
+    Ok(())
+}
 
-    if event.total_commits_count != 0 {
-        return Ok(Some(event.into()))
+/// Splits an embed into messages, the first of them mentioning `mentions`.
+fn webhook_messages(hook_config: &HookConfig, embed: Embed, mentions: &[DiscordTarget]) -> Vec<WebhookMessage> {
+    let mut messages = WebhookMessage::split(vec![embed], hook_config.username.clone(), hook_config.avatar_url.clone());
+    if let Some(first) = messages.first_mut() {
+        first.mention(mentions);
     }
+    messages
+}
 
-    Ok(None)
+fn to_json(message: &WebhookMessage) -> std::result::Result<String, DeliveryError> {
+    serde_json::to_string(message)
+        .map_err(|e| DeliveryError::InvalidMessage(format!("failed to serialize message: {}", e)))
 }
 
-async fn handle_issue_hook(payload: impl bytes::Buf) -> Result<Option<Embed>> {
-    use crate::models::gitlab::IssueEvent;
+/// Stores a message, the message itself is delivered already so failing to do so is only logged.
+async fn store_message(messages: &MessageIndex, hook_config: &HookConfig, key: &str, message: TrackedMessage) {
+    if let Err(err) = messages.lock().await.insert(&hook_config.id, key, message).await {
+        log::error!("Failed to store message of {} for hook {}: {}", key, hook_config.id, err);
+    }
+}
 
-    let reader = payload.reader();
-    let event: IssueEvent = serde_json::from_reader(reader)?;
+async fn forget_message(messages: &MessageIndex, hook_config: &HookConfig, key: &str) {
+    if let Err(err) = messages.lock().await.remove(&hook_config.id, key).await {
+        log::error!("Failed to remove message of {} for hook {}: {}", key, hook_config.id, err);
+    }
+}
 
-    if let "open" | "close" = &*event.attributes.action {
-        return Ok(Some(event.into()))
+/// Sends a request to Discord, waiting out rate limits.
+async fn send(client: &HttpClient, limiter: &outbound::OutboundLimiter, method: Method, uri: String, json: String) -> std::result::Result<Response, DeliveryError> {
+    // Held until the response arrived, so requests to the webhook go out one after the other
+    let _turn = limiter.acquire(&uri).await;
+
+    loop {
+        let res = client.request(method.clone(), uri.as_str(), json.clone()).await
+            .map_err(|e| DeliveryError::Request(format!("{:#}", e)))?;
+        limiter.update(&uri, res.headers());
+
+        match res.status() {
+            StatusCode::TOO_MANY_REQUESTS => {
+                let headers = res.headers().clone();
+                let body = hyper::body::to_bytes(res).await
+                    .map_err(|e| DeliveryError::InvalidRateLimit(format!("failed to read body: {}", e)))?;
+                let limited = Limited::from_response(&headers, &body)
+                    .ok_or_else(|| DeliveryError::InvalidRateLimit("no retry_after or reset header".into()))?;
+
+                log::warn!(
+                    "Reached {} Discord rate limit, retrying in {:.2} seconds",
+                    if limited.global { "global" } else { "webhook" },
+                    limited.retry_after.as_secs_f64(),
+                );
+                limiter.limited(&uri, limited);
+                limiter.wait(&uri).await;
+            },
+            status if !status.is_success() => {
+                log::error!("Headers:\n{:#?}", res.headers());
+                let body = hyper::body::to_bytes(res).await
+                    .map(|body| String::from_utf8_lossy(&body).into_owned())
+                    .unwrap_or_else(|e| format!("<failed to read body: {}>", e));
+                log::error!("Response Payload:\n{}", body);
+                return Err(DeliveryError::Rejected { status, body });
+            },
+            _ => return Ok(res),
+        }
     }
+}
 
-    Ok(None)
+/// Extends the path of a webhook URL, keeping any query it already has.
+fn webhook_uri(base: &str, path: &str, query: Option<&str>) -> String {
+    let (base, existing) = match base.find('?') {
+        Some(index) => (&base[..index], Some(&base[index + 1..])),
+        None => (base, None),
+    };
+
+    let query = existing.into_iter()
+        .chain(query)
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let mut uri = format!("{}{}", base.trim_end_matches('/'), path);
+    if !query.is_empty() {
+        uri.push('?');
+        uri.push_str(&query);
+    }
+    uri
+}
+
+fn handle_push_hook(event: PushEvent) -> std::result::Result<Option<Embed>, RenderError> {
+    Embed::try_from(event).map(Some)
 }
 
-async fn handle_merge_request_hook(payload: impl bytes::Buf) -> Result<Option<Embed>> {
-    use crate::models::gitlab::MergeRequestEvent;
+fn handle_tag_push_hook(event: TagPushEvent) -> std::result::Result<Option<Embed>, RenderError> {
+    Embed::try_from(event).map(Some)
+}
+
+fn handle_release_hook(event: ReleaseEvent) -> std::result::Result<Option<Embed>, RenderError> {
+    if let "create" | "update" = &*event.action {
+        return Embed::try_from(event).map(Some)
+    }
 
-    let reader = payload.reader();
-    let event: MergeRequestEvent = serde_json::from_reader(reader)?;
+    Ok(None)
+}
 
-    if let "open" | "close" | "merge" = &*event.attributes.action {
-        return Ok(Some(event.into()))
+fn handle_note_hook(event: NoteEvent, filter: &NoteFilter) -> std::result::Result<Option<Embed>, RenderError> {
+    if filter.matches(&event) {
+        return Embed::try_from(event).map(Some)
     }
 
     Ok(None)
 }
 
-async fn handle_pipeline_hook(payload: impl bytes::Buf) -> Result<Option<Embed>> {
-    use crate::models::gitlab::PipelineEvent;
+fn handle_issue_hook(event: IssueEvent, filter: &IssueFilter) -> std::result::Result<Option<Embed>, RenderError> {
+    if !filter.allows(&event.attributes.action) {
+        return Ok(None)
+    }
+
+    // Updates touching nothing we render, like time tracking, are not worth a message
+    if event.attributes.action == "update" && discord::describe_changes(&event.changes).is_empty() {
+        return Ok(None)
+    }
+
+    Embed::try_from(event).map(Some)
+}
+
+fn handle_merge_request_hook(event: MergeRequestEvent, filter: &MergeRequestFilter) -> std::result::Result<Option<Embed>, RenderError> {
+    if !filter.matches(&event) {
+        return Ok(None)
+    }
+
+    if event.action() == "update" && discord::describe_changes(&event.changes).is_empty() {
+        return Ok(None)
+    }
+
+    Embed::try_from(event).map(Some)
+}
+
+fn handle_job_hook(event: JobEvent, filter: &JobFilter) -> std::result::Result<Option<Embed>, RenderError> {
+    // Combined jobs are held back for the pipeline message instead
+    if !filter.matches(&event) || filter.combine_with_pipeline {
+        return Ok(None)
+    }
 
-    let reader = payload.reader();
-    let event: PipelineEvent = serde_json::from_reader(reader)?;
+    Embed::try_from(event).map(Some)
+}
 
+async fn handle_pipeline_hook(event: PipelineEvent, hook_config: &HookConfig, jobs: &JobCache) -> std::result::Result<Option<Embed>, RenderError> {
     if let "success" | "failed" = &*event.attributes.status {
-        return Ok(Some(event.into()))
+        let failed_jobs = jobs.lock().await.get(&hook_config.id, event.attributes.id).await;
+        let mut embed = Embed::try_from(event)?;
+
+        if !failed_jobs.is_empty() {
+            embed.append_section("Failed jobs", &failed_jobs);
+        }
+
+        return Ok(Some(embed))
+    }
+
+    // Live updates report every state, each replacing the previous one
+    if hook_config.filters.pipelines.live_updates {
+        return Embed::try_from(event).map(Some)
+    }
+
+    Ok(None)
+}
+
+fn pipeline_finished(event: &PipelineEvent) -> bool {
+    matches!(&*event.attributes.status, "success" | "failed" | "canceled" | "skipped")
+}
+
+fn handle_deployment_hook(event: DeploymentEvent) -> std::result::Result<Option<Embed>, RenderError> {
+    if let "running" | "success" | "failed" | "canceled" = &*event.status {
+        return Embed::try_from(event).map(Some)
+    }
+
+    Ok(None)
+}
+
+fn handle_feature_flag_hook(event: FeatureFlagEvent) -> std::result::Result<Option<Embed>, RenderError> {
+    Embed::try_from(event).map(Some)
+}
+
+fn handle_wiki_page_hook(event: WikiPageEvent) -> std::result::Result<Option<Embed>, RenderError> {
+    if let "create" | "update" | "delete" = &*event.attributes.action {
+        return Embed::try_from(event).map(Some)
     }
 
     Ok(None)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_uri_keeps_query() {
+        let base = "https://discord.com/api/webhooks/1/token";
+        assert_eq!(webhook_uri(base, "", Some("wait=true")), "https://discord.com/api/webhooks/1/token?wait=true");
+        assert_eq!(
+            webhook_uri(&format!("{}?thread_id=5", base), "/messages/9", None),
+            "https://discord.com/api/webhooks/1/token/messages/9?thread_id=5",
+        );
+    }
+
+    #[test]
+    fn thread_keys_and_names() {
+        let project = Project {
+            name: "project".into(),
+            web_url: "https://gitlab.com/group/project".into(),
+            path_with_namespace: "group/project".into(),
+            avatar_url: String::new(),
+        };
+
+        let thread = Thread::merge_request(&project, 4, &"a".repeat(200), false);
+        assert_eq!(thread.key, "group/project!4");
+        assert_eq!(thread.name.chars().count(), Thread::NAME_MAX_LENGTH);
+        assert!(thread.name.starts_with("!4 aaa"));
+
+        assert_eq!(Thread::issue(&project, 4, "Bug", true).key, "group/project#4");
+    }
+}
@@ -0,0 +1,36 @@
+/// Sample payloads of the GitLab events we handle, taken from the test fixtures.
+const GITLAB_SAMPLES: &[(&str, &str)] = &[
+    ("Push Hook", include_str!("../tests/data/push_event.json")),
+    ("Tag Push Hook", include_str!("../tests/data/tag_push_event.json")),
+    ("Release Hook", include_str!("../tests/data/release_created_event.json")),
+    ("Note Hook", include_str!("../tests/data/note_mr_event.json")),
+    ("Issue Hook", include_str!("../tests/data/issue_opened_event.json")),
+    ("Merge Request Hook", include_str!("../tests/data/mr_opened_event.json")),
+    ("Job Hook", include_str!("../tests/data/job_failed_event.json")),
+    ("Pipeline Hook", include_str!("../tests/data/pipeline_failed_event.json")),
+    ("Deployment Hook", include_str!("../tests/data/deployment_event.json")),
+    ("Feature Flag Hook", include_str!("../tests/data/feature_flag_event.json")),
+    ("Wiki Page Hook", include_str!("../tests/data/wiki_page_event.json")),
+];
+
+/// The built-in sample of a GitLab event, e.g. `Push Hook`.
+pub fn gitlab(event: &str) -> Option<&'static str> {
+    GITLAB_SAMPLES.iter()
+        .find(|(name, _)| *name == event)
+        .map(|(_, payload)| *payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_json() {
+        for (event, payload) in GITLAB_SAMPLES {
+            assert!(serde_json::from_str::<serde_json::Value>(payload).is_ok(), "{} sample", event);
+        }
+
+        assert!(gitlab("Push Hook").is_some());
+        assert!(gitlab("System Hook").is_none());
+    }
+}
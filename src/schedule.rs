@@ -0,0 +1,145 @@
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Datelike, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+
+/// Longest a window can stay closed, a week plus the hours of a window wrapping past midnight
+const MAX_CLOSED_HOURS: i64 = 8 * 24;
+
+/// The hours and days a hook delivers events in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Schedule {
+    /// Time zone the hours and days are in, e.g. `Europe/Berlin`
+    pub timezone: Tz,
+    pub days: Vec<Weekday>,
+    /// First hour of the window
+    pub start: u32,
+    /// Hour the window closes at, windows ending before they start span midnight
+    pub end: u32,
+    /// What happens to events outside the window
+    pub outside: OutsidePolicy,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            timezone: Tz::UTC,
+            days: vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri],
+            start: 8,
+            end: 18,
+            outside: OutsidePolicy::Queue,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutsidePolicy {
+    Drop,
+    /// Deliver the events once the window opens
+    Queue,
+    /// Collect the events into a digest sent once the window opens
+    Digest,
+}
+
+impl Schedule {
+    /// Whether events are delivered right away at `now`.
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.timezone);
+        let hour = local.hour();
+        let today = self.days.contains(&local.weekday());
+
+        match self.start.cmp(&self.end) {
+            std::cmp::Ordering::Less => today && hour >= self.start && hour < self.end,
+            // The hours after midnight belong to the window of the day before
+            std::cmp::Ordering::Greater => (today && hour >= self.start)
+                || (hour < self.end && self.days.contains(&local.weekday().pred())),
+            std::cmp::Ordering::Equal => today,
+        }
+    }
+
+    /// The next time the window opens after `now`, `None` if it never does.
+    pub fn next_open(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        // Windows open at the start of an hour in their time zone, which isn't always a full hour in UTC
+        let local = now.with_timezone(&self.timezone);
+        let hour = now - chrono::Duration::seconds((local.minute() * 60 + local.second()).into())
+            - chrono::Duration::nanoseconds(local.nanosecond().into());
+
+        (1..=MAX_CLOSED_HOURS)
+            .map(|hours| hour + chrono::Duration::hours(hours))
+            .find(|time| self.is_open(*time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        let naive = chrono::NaiveDate::from_ymd_opt(2021, 3, day).unwrap()
+            .and_hms_opt(hour, minute, 0).unwrap();
+        Utc.from_utc_datetime(&naive)
+    }
+
+    #[test]
+    fn windows_follow_the_time_zone() {
+        let schedule = Schedule {
+            timezone: Tz::Europe__Berlin,
+            ..Schedule::default()
+        };
+
+        // 08:30 in Berlin on Wednesday, 10 March
+        assert!(schedule.is_open(utc(10, 7, 30)));
+        assert!(!schedule.is_open(utc(10, 17, 0)));
+        // Friday evening, the window opens on Monday at 08:00 in Berlin
+        assert_eq!(schedule.next_open(utc(12, 17, 0)), Some(utc(15, 7, 0)));
+    }
+
+    #[test]
+    fn windows_open_on_the_local_hour() {
+        let schedule = Schedule {
+            timezone: Tz::Asia__Kolkata,
+            ..Schedule::default()
+        };
+
+        // 06:30 in Kolkata on Wednesday, 10 March, the window opens at 08:00 there
+        assert!(!schedule.is_open(utc(10, 1, 0)));
+        assert_eq!(schedule.next_open(utc(10, 1, 0)), Some(utc(10, 2, 30)));
+        assert_eq!(schedule.next_open(utc(10, 1, 45)), Some(utc(10, 2, 30)));
+    }
+
+    #[test]
+    fn windows_can_span_midnight() {
+        let schedule = Schedule {
+            days: vec![Weekday::Sat, Weekday::Sun],
+            start: 22,
+            end: 2,
+            ..Schedule::default()
+        };
+
+        // Saturday, 13 March
+        assert!(schedule.is_open(utc(13, 23, 0)));
+        assert!(schedule.is_open(utc(14, 1, 59)));
+        assert!(!schedule.is_open(utc(13, 12, 0)));
+        assert_eq!(schedule.next_open(utc(13, 12, 0)), Some(utc(13, 22, 0)));
+    }
+
+    #[test]
+    fn hours_after_midnight_belong_to_the_day_before() {
+        let schedule = Schedule {
+            days: vec![Weekday::Fri],
+            start: 22,
+            end: 2,
+            ..Schedule::default()
+        };
+
+        // Friday, 12 March until Saturday morning
+        assert!(schedule.is_open(utc(12, 22, 0)));
+        assert!(schedule.is_open(utc(13, 1, 0)));
+        assert!(!schedule.is_open(utc(13, 22, 0)));
+        // Friday's early hours are the end of Thursday's window
+        assert!(!schedule.is_open(utc(12, 1, 0)));
+        assert_eq!(schedule.next_open(utc(13, 2, 0)), Some(utc(19, 22, 0)));
+    }
+}
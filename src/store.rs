@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     io::{self, Write},
     fs::{self, File, OpenOptions},
     path::{Path, PathBuf},
@@ -9,9 +9,17 @@ use std::{
 use anyhow::anyhow;
 use serde::{Serialize, Deserialize};
 use chrono::DateTime;
-use crate::Result;
+use crate::{
+    filters::EventFilters,
+    digest::{DigestConfig, SummaryConfig},
+    schedule::Schedule,
+    models::{UserMapping, DiscordTarget},
+    Result,
+};
 
 const STORAGE_ROOT: &str = "./data";
+const DELIVERY_HISTORY_LENGTH: usize = 100;
+const USER_MAPPINGS_FILE: &str = "mentions.json";
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
 #[serde(transparent)]
@@ -39,25 +47,78 @@ impl TryFrom<&str> for HookId {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// Drop redelivered events
+    Skip,
+    /// Deliver redelivered events again, but flag them in the history
+    Deliver,
+}
+
+impl Default for DuplicatePolicy {
+    fn default() -> Self {
+        Self::Skip
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HookConfig {
     pub id: HookId,
     pub description: String,
     pub gitlab_token: String,
     pub discord_url: String,
+    #[serde(default)]
+    pub allowed_ips: Vec<ipnet::IpNet>,
+    #[serde(default)]
+    pub duplicates: DuplicatePolicy,
+    #[serde(default)]
+    pub filters: EventFilters,
+    /// Post the events of each merge request and issue into a thread of its own
+    #[serde(default)]
+    pub threads: bool,
+    /// Overrides the name the webhook posts with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Overrides the avatar the webhook posts with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    #[serde(default)]
+    pub digest: DigestConfig,
+    #[serde(default)]
+    pub summary: SummaryConfig,
+    /// Hours and days events are delivered in, always when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+    /// Set once deliveries kept failing, events are ignored until the hook is enabled again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disabled: Option<Disabled>,
     #[serde(default = "chrono::Utc::now")]
     pub created_at: DateTime<chrono::Utc>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Disabled {
+    pub reason: String,
+    pub disabled_at: DateTime<chrono::Utc>,
+}
+
 #[derive(Clone, Default)]
 pub struct HookRegistry {
     inner: HashMap<HookId, HookConfig>,
+    /// Discord mentions by lowercase GitLab username or email, shared by all hooks
+    mentions: HashMap<String, DiscordTarget>,
 }
 
 impl HookRegistry {
     pub fn load() -> Result<Self> {
         let inner = load_all_hook_configs()?;
-        Ok(Self { inner })
+        let mentions = load_user_mappings()?
+            .into_iter()
+            .map(|mapping| (mapping.gitlab.to_lowercase(), mapping.discord))
+            .collect();
+
+        Ok(Self { inner, mentions })
     }
 
     pub async fn all(&self) -> Vec<HookConfig> {
@@ -84,6 +145,34 @@ impl HookRegistry {
         Ok(())
     }
 
+    /// Disables every hook posting to `discord_url`, returning the hooks that were disabled.
+    pub async fn disable_destination(&mut self, discord_url: &str, disabled: Disabled) -> Result<Vec<HookConfig>> {
+        let mut changed = Vec::new();
+
+        for config in self.inner.values_mut() {
+            if config.discord_url == discord_url && config.disabled.is_none() {
+                config.disabled = Some(disabled.clone());
+                store_hook_config(config)?;
+                changed.push(config.clone());
+            }
+        }
+
+        Ok(changed)
+    }
+
+    pub async fn enable<I>(&mut self, id: I) -> Result<HookConfig>
+    where HookId: TryFrom<I>
+    {
+        let id = HookId::try_from(id).or_else(|_| Err(anyhow!("Failed to parse id")))?;
+        let config = self.inner.get_mut(&id)
+            .ok_or_else(|| anyhow!("No hook config found for id"))?;
+
+        config.disabled = None;
+        store_hook_config(config)?;
+
+        Ok(config.clone())
+    }
+
     pub async fn delete<I>(&mut self, id: I) -> Result<()>
     where HookId: TryFrom<I>
     {
@@ -93,6 +182,296 @@ impl HookRegistry {
 
         Ok(())
     }
+
+    pub async fn user_mappings(&self) -> Vec<UserMapping> {
+        let mut mappings = self.mentions.iter()
+            .map(|(gitlab, discord)| UserMapping { gitlab: gitlab.clone(), discord: discord.clone() })
+            .collect::<Vec<_>>();
+        mappings.sort_by(|a, b| a.gitlab.cmp(&b.gitlab));
+        mappings
+    }
+
+    pub async fn insert_user_mapping(&mut self, mapping: UserMapping) -> Result<()> {
+        self.mentions.insert(mapping.gitlab.to_lowercase(), mapping.discord);
+        store_user_mappings(&self.user_mappings().await)?;
+
+        Ok(())
+    }
+
+    /// Removes the mapping of a GitLab user, returning whether there was one.
+    pub async fn delete_user_mapping(&mut self, gitlab: &str) -> Result<bool> {
+        if self.mentions.remove(&gitlab.to_lowercase()).is_none() {
+            return Ok(false);
+        }
+        store_user_mappings(&self.user_mappings().await)?;
+
+        Ok(true)
+    }
+
+    /// Looks up whom to mention for GitLab usernames and emails, skipping unmapped ones.
+    pub async fn resolve_mentions(&self, gitlab_users: &[String]) -> Vec<DiscordTarget> {
+        let mut targets = Vec::new();
+
+        for user in gitlab_users {
+            if let Some(target) = self.mentions.get(&user.to_lowercase()) {
+                if !targets.contains(target) {
+                    targets.push(target.clone());
+                }
+            }
+        }
+
+        targets
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Queued,
+    Delivered,
+    Ignored,
+    Duplicate,
+    /// Collected into a digest
+    Batched,
+    Failed,
+}
+
+impl DeliveryStatus {
+    /// Whether the event was taken on for delivery, so sending it again makes it a redelivery.
+    pub fn accepted(self) -> bool {
+        matches!(self, Self::Queued | Self::Batched | Self::Delivered)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryRecord {
+    pub id: uuid::Uuid,
+    pub event: String,
+    pub event_id: Option<String>,
+    pub status: DeliveryStatus,
+    #[serde(default)]
+    pub redelivery: bool,
+    /// Sent through the API to try out the hook
+    #[serde(default)]
+    pub test: bool,
+    pub received_at: DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl DeliveryRecord {
+    pub fn new(event: &str, event_id: Option<String>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4(),
+            event: event.to_string(),
+            event_id,
+            status: DeliveryStatus::Queued,
+            redelivery: false,
+            test: false,
+            received_at: chrono::Utc::now(),
+            detail: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct HookDeliveries {
+    /// Ids of the events accepted within the deduplication window
+    seen: HashMap<String, DateTime<chrono::Utc>>,
+    history: VecDeque<DeliveryRecord>,
+}
+
+pub struct DeliveryLog {
+    window: chrono::Duration,
+    inner: HashMap<HookId, HookDeliveries>,
+}
+
+impl DeliveryLog {
+    pub fn load(window: chrono::Duration) -> Result<Self> {
+        let mut inner = load_all_deliveries()?;
+
+        // Queued and held events only lived in memory, they are gone with the process that accepted them
+        for (hook_id, deliveries) in inner.iter_mut() {
+            let mut lost = false;
+            for record in deliveries.history.iter_mut().filter(|r| r.status == DeliveryStatus::Queued) {
+                record.status = DeliveryStatus::Failed;
+                record.detail = Some("Dropped by a restart before it was delivered".into());
+                if let Some(event_id) = &record.event_id {
+                    deliveries.seen.remove(event_id);
+                }
+                lost = true;
+            }
+
+            if lost {
+                log::warn!("Dropped events of hook {} queued before the restart", hook_id);
+                store_deliveries(hook_id, deliveries)?;
+            }
+        }
+
+        Ok(Self { window, inner })
+    }
+
+    pub async fn is_duplicate(&self, hook_id: &HookId, event_id: &str) -> bool {
+        let cutoff = chrono::Utc::now() - self.window;
+
+        let seen_at = self.inner.get(hook_id)
+            .and_then(|deliveries| deliveries.seen.get(event_id));
+
+        matches!(seen_at, Some(seen_at) if *seen_at > cutoff)
+    }
+
+    pub async fn record(&mut self, hook_id: &HookId, record: DeliveryRecord) -> Result<()> {
+        let now = chrono::Utc::now();
+        let cutoff = now - self.window;
+        let deliveries = self.inner.entry(hook_id.clone()).or_default();
+
+        deliveries.seen.retain(|_, seen_at| *seen_at > cutoff);
+        // Rejected events may be sent again once the problem is fixed
+        if let (Some(event_id), true) = (&record.event_id, record.status.accepted()) {
+            deliveries.seen.entry(event_id.clone()).or_insert(now);
+        }
+
+        deliveries.history.push_front(record);
+        deliveries.history.truncate(DELIVERY_HISTORY_LENGTH);
+
+        store_deliveries(hook_id, deliveries)?;
+        Ok(())
+    }
+
+    pub async fn update(&mut self, hook_id: &HookId, record_id: uuid::Uuid, status: DeliveryStatus, detail: Option<String>) -> Result<()> {
+        if let Some(deliveries) = self.inner.get_mut(hook_id) {
+            if let Some(record) = deliveries.history.iter_mut().find(|r| r.id == record_id) {
+                record.status = status;
+                record.detail = detail;
+
+                if let (Some(event_id), false) = (&record.event_id, status.accepted()) {
+                    deliveries.seen.remove(event_id);
+                }
+                store_deliveries(hook_id, deliveries)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn history(&self, hook_id: &HookId) -> Vec<DeliveryRecord> {
+        self.inner.get(hook_id)
+            .map(|deliveries| deliveries.history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub async fn delete(&mut self, hook_id: &HookId) -> Result<()> {
+        if self.inner.remove(hook_id).is_some() {
+            delete_deliveries(hook_id)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Failed jobs held back until the pipeline they belong to finishes.
+#[derive(Default)]
+pub struct JobCache {
+    inner: HashMap<(HookId, u64), (DateTime<chrono::Utc>, Vec<String>)>,
+}
+
+impl JobCache {
+    const MAX_AGE_HOURS: i64 = 24;
+
+    pub async fn push(&mut self, hook_id: &HookId, pipeline_id: u64, line: String) {
+        let now = chrono::Utc::now();
+        let cutoff = now - chrono::Duration::hours(Self::MAX_AGE_HOURS);
+        self.inner.retain(|_, (added_at, _)| *added_at > cutoff);
+
+        self.inner.entry((hook_id.clone(), pipeline_id))
+            .or_insert_with(|| (now, Vec::new()))
+            .1
+            .push(line);
+    }
+
+    pub async fn get(&self, hook_id: &HookId, pipeline_id: u64) -> Vec<String> {
+        self.inner.get(&(hook_id.clone(), pipeline_id))
+            .map(|(_, lines)| lines.clone())
+            .unwrap_or_default()
+    }
+
+    pub async fn remove(&mut self, hook_id: &HookId, pipeline_id: u64) {
+        self.inner.remove(&(hook_id.clone(), pipeline_id));
+    }
+}
+
+/// A Discord message that is edited as the event it reports on progresses,
+/// or the first message of a thread that later events are posted into.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackedMessage {
+    pub message_id: String,
+    /// The thread the message was posted in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+    /// Set once the final state was posted, later updates are stale
+    #[serde(default)]
+    pub finished: bool,
+    pub updated_at: DateTime<chrono::Utc>,
+}
+
+impl TrackedMessage {
+    pub fn new(message_id: String, thread_id: Option<String>, finished: bool) -> Self {
+        Self {
+            message_id,
+            thread_id,
+            finished,
+            updated_at: chrono::Utc::now(),
+        }
+    }
+}
+
+/// Maps keys like `pipeline/31` or `group/project!4` to the messages posted for them, per hook.
+pub struct MessageIndex {
+    inner: HashMap<HookId, HashMap<String, TrackedMessage>>,
+}
+
+impl MessageIndex {
+    const MAX_AGE_DAYS: i64 = 30;
+
+    pub fn load() -> Result<Self> {
+        let inner = load_all_messages()?;
+        Ok(Self { inner })
+    }
+
+    pub async fn get(&self, hook_id: &HookId, key: &str) -> Option<TrackedMessage> {
+        self.inner.get(hook_id)
+            .and_then(|messages| messages.get(key))
+            .cloned()
+    }
+
+    pub async fn insert(&mut self, hook_id: &HookId, key: &str, message: TrackedMessage) -> Result<()> {
+        let cutoff = chrono::Utc::now() - chrono::Duration::days(Self::MAX_AGE_DAYS);
+        let messages = self.inner.entry(hook_id.clone()).or_default();
+
+        messages.retain(|_, message| message.updated_at > cutoff);
+        messages.insert(key.to_string(), message);
+
+        store_messages(hook_id, messages)?;
+        Ok(())
+    }
+
+    pub async fn remove(&mut self, hook_id: &HookId, key: &str) -> Result<()> {
+        if let Some(messages) = self.inner.get_mut(hook_id) {
+            if messages.remove(key).is_some() {
+                store_messages(hook_id, messages)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn delete(&mut self, hook_id: &HookId) -> Result<()> {
+        if self.inner.remove(hook_id).is_some() {
+            delete_messages(hook_id)?;
+        }
+
+        Ok(())
+    }
 }
 
 fn store_hook_config(config: &HookConfig) -> io::Result<()> {
@@ -137,9 +516,109 @@ fn load_all_hook_configs() -> io::Result<HashMap<HookId, HookConfig>> {
     Ok(configs)
 }
 
+fn store_user_mappings(mappings: &[UserMapping]) -> io::Result<()> {
+    let json = serde_json::to_string_pretty(mappings)?;
+    fs::create_dir_all(STORAGE_ROOT)?;
+    fs::write(PathBuf::from(STORAGE_ROOT).join(USER_MAPPINGS_FILE), json)
+}
+
+fn load_user_mappings() -> io::Result<Vec<UserMapping>> {
+    let path = PathBuf::from(STORAGE_ROOT).join(USER_MAPPINGS_FILE);
+    match File::open(&path) {
+        Ok(file) => {
+            log::debug!("Loading user mappings from {}", path.display());
+            Ok(serde_json::from_reader(file)?)
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
 fn get_hook_path() -> io::Result<PathBuf> {
     let path = PathBuf::from(STORAGE_ROOT)
         .join("hooks");
     fs::create_dir_all(&path)?;
     Ok(path)
 }
+
+fn store_deliveries(id: &HookId, deliveries: &HookDeliveries) -> io::Result<()> {
+    let json = serde_json::to_string(deliveries)?;
+    let outfile = get_deliveries_path()?.join(format!("{}.json", id));
+    fs::write(outfile, json)
+}
+
+fn delete_deliveries(id: &HookId) -> io::Result<()> {
+    let path = get_deliveries_path()?.join(format!("{}.json", id));
+    log::debug!("Deleting delivery history at {}", path.display());
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn load_all_deliveries() -> io::Result<HashMap<HookId, HookDeliveries>> {
+    let mut all = HashMap::new();
+    let path = get_deliveries_path()?;
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let id = path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| HookId::try_from(stem).ok());
+
+        if let (true, Some(id)) = (path.is_file(), id) {
+            log::debug!("Loading delivery history from {}", path.display());
+            let deliveries = serde_json::from_reader(File::open(&path)?)?;
+            all.insert(id, deliveries);
+        }
+    }
+
+    Ok(all)
+}
+
+fn get_deliveries_path() -> io::Result<PathBuf> {
+    let path = PathBuf::from(STORAGE_ROOT)
+        .join("deliveries");
+    fs::create_dir_all(&path)?;
+    Ok(path)
+}
+
+fn store_messages(id: &HookId, messages: &HashMap<String, TrackedMessage>) -> io::Result<()> {
+    let json = serde_json::to_string(messages)?;
+    let outfile = get_messages_path()?.join(format!("{}.json", id));
+    fs::write(outfile, json)
+}
+
+fn delete_messages(id: &HookId) -> io::Result<()> {
+    let path = get_messages_path()?.join(format!("{}.json", id));
+    log::debug!("Deleting tracked messages at {}", path.display());
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn load_all_messages() -> io::Result<HashMap<HookId, HashMap<String, TrackedMessage>>> {
+    let mut all = HashMap::new();
+    let path = get_messages_path()?;
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let id = path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| HookId::try_from(stem).ok());
+
+        if let (true, Some(id)) = (path.is_file(), id) {
+            log::debug!("Loading tracked messages from {}", path.display());
+            let messages = serde_json::from_reader(File::open(&path)?)?;
+            all.insert(id, messages);
+        }
+    }
+
+    Ok(all)
+}
+
+fn get_messages_path() -> io::Result<PathBuf> {
+    let path = PathBuf::from(STORAGE_ROOT)
+        .join("messages");
+    fs::create_dir_all(&path)?;
+    Ok(path)
+}